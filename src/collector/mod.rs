@@ -1,5 +1,5 @@
 use reqwest::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -28,21 +28,47 @@ pub struct CollectionBeatmaps {
     pub beatmapsets: Vec<Beatmapset>,
 }
 
+/// A page of `beatmapsv3`, the next one starting at `next_page_cursor`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BeatmapsPage {
+    #[serde(default)]
+    beatmaps: Vec<Beatmap>,
+    #[serde(default)]
+    beatmapsets: Vec<Beatmapset>,
+    #[serde(default)]
+    has_more: bool,
+    next_page_cursor: Option<serde_json::Value>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct CollectionUploader {
     pub username: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
-enum Gamemode {
+pub enum Gamemode {
     Osu,
     Taiko,
     Fruits,
     Mania,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl Gamemode {
+    /// From osu!'s own numbering (0 to 3).
+    pub fn from_raw(raw: u8) -> Option<Gamemode> {
+        match raw {
+            0 => Some(Gamemode::Osu),
+            1 => Some(Gamemode::Taiko),
+            2 => Some(Gamemode::Fruits),
+            3 => Some(Gamemode::Mania),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum BeatmapStatus {
     Ranked,
@@ -52,6 +78,7 @@ pub enum BeatmapStatus {
     Unranked,
     Pending,
     Approved,
+    #[value(name = "notsubmitted")]
     NotSubmitted,
     Wip,
 }
@@ -60,6 +87,18 @@ pub enum BeatmapStatus {
 pub struct Beatmap {
     pub checksum: String,
     pub version: String,
+    pub mode: Option<Gamemode>,
+    pub status: Option<BeatmapStatus>,
+    pub difficulty_rating: Option<f32>,
+    pub bpm: Option<f32>,
+    /// In seconds.
+    pub total_length: Option<u32>,
+    pub ar: Option<f32>,
+    /// OD
+    pub accuracy: Option<f32>,
+    pub cs: Option<f32>,
+    /// HP
+    pub drain: Option<f32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -67,9 +106,110 @@ pub struct Beatmapset {
     pub id: i32,
     pub artist: String,
     pub title: String,
+    pub status: Option<BeatmapStatus>,
+}
+
+/// A tournament mappool, its beatmaps grouped by round and then by mod (NM, HD, ...).
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tournament {
+    pub id: i32,
+    pub name: String,
+    pub uploader: CollectionUploader,
+    pub rounds: Vec<TournamentRound>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TournamentRound {
+    pub round: String,
+    pub mods: Vec<TournamentMod>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TournamentMod {
+    pub maps: Vec<TournamentBeatmap>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TournamentBeatmap {
+    #[serde(flatten)]
+    pub beatmap: Beatmap,
+    pub beatmapset: Beatmapset,
+}
+
+impl TournamentRound {
+    fn maps(&self) -> impl Iterator<Item = &TournamentBeatmap> {
+        self.mods.iter().flat_map(|m| m.maps.iter())
+    }
+
+    /// The round's beatmaps grouped by beatmapset, as they are in a collection.
+    pub fn beatmapsets(&self) -> Vec<CollectionInfoBeatmapsets> {
+        let mut beatmapsets: Vec<CollectionInfoBeatmapsets> = vec![];
+
+        for map in self.maps() {
+            let beatmap = CollectionInfoBeatmap {
+                checksum: map.beatmap.checksum.clone(),
+            };
+
+            match beatmapsets.iter_mut().find(|s| s.id == map.beatmapset.id) {
+                Some(beatmapset) => beatmapset.beatmaps.push(beatmap),
+                None => beatmapsets.push(CollectionInfoBeatmapsets {
+                    id: map.beatmapset.id,
+                    beatmaps: vec![beatmap],
+                }),
+            }
+        }
+
+        beatmapsets
+    }
+}
+
+impl Tournament {
+    /// Metadata of every beatmap in the mappool, like `beatmapsv3` gives for collections.
+    pub fn beatmaps(&self) -> CollectionBeatmaps {
+        let maps = self
+            .rounds
+            .iter()
+            .flat_map(|r| r.maps())
+            .collect::<Vec<_>>();
+
+        CollectionBeatmaps {
+            beatmaps: maps.iter().map(|m| m.beatmap.clone()).collect(),
+            beatmapsets: maps.iter().map(|m| m.beatmapset.clone()).collect(),
+        }
+    }
 }
 
 const BASE_URL: &str = "https://osucollector.com/api";
+const BEATMAPS_PER_PAGE: u32 = 100;
+
+/// Accepts a collection ID, or its URL (`https://osucollector.com/collections/<ID>/<NAME>`).
+pub fn parse_id(value: &str) -> Result<i32, String> {
+    parse_url_id(value, "collections").ok_or(format!(
+        "'{}' isn't an osu!collector collection ID or URL",
+        value
+    ))
+}
+
+/// Accepts a tournament ID, or its URL (`https://osucollector.com/tournaments/<ID>`).
+pub fn parse_tournament_id(value: &str) -> Result<i32, String> {
+    parse_url_id(value, "tournaments").ok_or(format!(
+        "'{}' isn't an osu!collector tournament ID or URL",
+        value
+    ))
+}
+
+fn parse_url_id(value: &str, kind: &str) -> Option<i32> {
+    let value = value.trim();
+
+    if let Ok(id) = value.parse::<i32>() {
+        return Some(id);
+    }
+
+    value
+        .split_once(&format!("osucollector.com/{}/", kind))
+        .and_then(|(_, path)| path.split(['/', '?', '#']).next())
+        .and_then(|id| id.parse::<i32>().ok())
+}
 
 pub async fn get_info(id: i32) -> Result<Collection, Error> {
     let client = reqwest::Client::new();
@@ -84,15 +224,99 @@ pub async fn get_info(id: i32) -> Result<Collection, Error> {
     Ok(response)
 }
 
-pub async fn get_beatmaps(id: i32) -> Result<CollectionBeatmaps, Error> {
+pub async fn get_tournament(id: i32) -> Result<Tournament, Error> {
     let client = reqwest::Client::new();
 
     let response = client
-        .get(format!("{}/collections/{}/beatmapsv3", BASE_URL, id))
+        .get(format!("{}/tournaments/{}", BASE_URL, id))
         .send()
         .await?
-        .json::<CollectionBeatmaps>()
+        .json::<Tournament>()
         .await?;
 
     Ok(response)
 }
+
+/// Fetches every page of the collection's beatmaps, merged together.
+pub async fn get_beatmaps(id: i32) -> Result<CollectionBeatmaps, Error> {
+    let client = reqwest::Client::new();
+    let mut beatmaps = CollectionBeatmaps {
+        beatmaps: vec![],
+        beatmapsets: vec![],
+    };
+    let mut cursor: Option<String> = None;
+
+    loop {
+        let mut request = client
+            .get(format!("{}/collections/{}/beatmapsv3", BASE_URL, id))
+            .query(&[("perPage", BEATMAPS_PER_PAGE)]);
+
+        // encoded by reqwest, cursors aren't necessarily URL-safe
+        if let Some(cursor) = &cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+
+        let mut page = request.send().await?.json::<BeatmapsPage>().await?;
+        beatmaps.beatmaps.append(&mut page.beatmaps);
+        beatmaps.beatmapsets.append(&mut page.beatmapsets);
+
+        let next_cursor = page.next_page_cursor.map(|cursor| match cursor {
+            serde_json::Value::String(cursor) => cursor,
+            cursor => cursor.to_string(),
+        });
+
+        // a repeated cursor would loop forever
+        if !page.has_more || next_cursor.is_none() || next_cursor == cursor {
+            break;
+        }
+
+        cursor = next_cursor;
+    }
+
+    Ok(beatmaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bare_ids() {
+        assert_eq!(parse_id("6220"), Ok(6220));
+        assert_eq!(parse_id(" 6220 "), Ok(6220));
+        assert_eq!(parse_tournament_id("142"), Ok(142));
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            parse_id("https://osucollector.com/collections/6220/tech-maps"),
+            Ok(6220)
+        );
+        assert_eq!(parse_id("osucollector.com/collections/6220"), Ok(6220));
+        assert_eq!(
+            parse_tournament_id("https://osucollector.com/tournaments/142/owc-2023"),
+            Ok(142)
+        );
+    }
+
+    #[test]
+    fn ignores_query_and_fragment() {
+        assert_eq!(
+            parse_id("https://osucollector.com/collections/6220?page=2"),
+            Ok(6220)
+        );
+        assert_eq!(
+            parse_id("https://osucollector.com/collections/6220#beatmaps"),
+            Ok(6220)
+        );
+    }
+
+    #[test]
+    fn rejects_other_kinds_and_garbage() {
+        assert!(parse_id("https://osucollector.com/tournaments/142").is_err());
+        assert!(parse_tournament_id("https://osucollector.com/collections/6220").is_err());
+        assert!(parse_id("https://osucollector.com/collections/abc").is_err());
+        assert!(parse_id("").is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use std::{fmt::Write, path::PathBuf, process::ExitCode};
+
+use clap::Subcommand;
+use osu_db::{CollectionList, collection::Collection};
+use sanitise_file_name::sanitise;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    config,
+    utilities::{
+        collection::{
+            CollectionChange, CollectionStore, add_to_collection, create_collection,
+            remove_from_collection, sync_collection,
+        },
+        osdb::{self, OsdbBeatmap, OsdbCollection},
+        osu::{Library, load_library},
+    },
+};
+
+#[derive(Subcommand)]
+pub enum CollectionsCommand {
+    /// List the collections with how many beatmaps they have
+    List,
+    /// Export a collection (or all of them)
+    Export {
+        /// Name of the collection, every collection is exported when it's left out
+        name: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = ExportFormat::Text)]
+        format: ExportFormat,
+
+        /// Where to write the export, instead of the standard output (or `<name>.osdb`)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Merge the collections of a JSON export into collection.db
+    Import {
+        file: PathBuf,
+
+        /// Make the collections match the file exactly, removing beatmaps that aren't in it
+        #[arg(long)]
+        sync: bool,
+    },
+    /// Rename a collection
+    Rename { name: String, new_name: String },
+    /// Delete collections
+    Delete {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Merge collections into one (created when it doesn't exist), deleting the merged ones
+    Merge {
+        #[arg(required = true)]
+        names: Vec<String>,
+
+        /// Collection receiving the beatmaps
+        #[arg(long)]
+        into: String,
+
+        /// Keep the merged collections
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Remove the duplicate hashes of a collection (or all of them)
+    Dedupe { name: Option<String> },
+    /// Remove the hashes osu!.db doesn't know from a collection (or all of them),
+    /// i.e. beatmaps that aren't installed anymore
+    Prune { name: Option<String> },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// The hashes, one per line
+    Text,
+    /// Every collection with its beatmaps, resolved through osu!.db when it's possible
+    Json,
+    /// A beatmap per line, resolved through osu!.db when it's possible
+    Csv,
+    /// Collection Manager's format, with the beatmap IDs and metadata osu! knows about
+    Osdb,
+}
+
+/// A collection as it's exported to (and imported from) JSON.
+#[derive(Deserialize, Serialize)]
+struct ExportedCollection {
+    name: String,
+    beatmaps: Vec<ExportedBeatmap>,
+}
+
+/// Only `checksum` is needed, the rest is what osu!.db knows about the beatmap.
+#[derive(Deserialize, Serialize)]
+struct ExportedBeatmap {
+    checksum: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beatmapset_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beatmap_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+pub async fn run(command: &CollectionsCommand) -> ExitCode {
+    match command {
+        CollectionsCommand::List => {
+            let Some(list) = read_list() else {
+                return ExitCode::FAILURE;
+            };
+            let library = load_library(&config::get().osu.osu_db_path).await.ok();
+
+            for collection in &list.collections {
+                let missing = library.as_ref().map(|library| {
+                    collection
+                        .beatmap_hashes
+                        .iter()
+                        .flatten()
+                        .filter(|c| !library.beatmaps.contains_key(*c))
+                        .count()
+                });
+
+                println!(
+                    "{} ({} beatmaps{})",
+                    collection.name.as_deref().unwrap_or_default(),
+                    collection.beatmap_hashes.len(),
+                    match missing {
+                        Some(missing) if missing > 0 => format!(", {} missing", missing),
+                        _ => String::new(),
+                    }
+                );
+            }
+
+            ExitCode::SUCCESS
+        }
+        CollectionsCommand::Export {
+            name,
+            format,
+            output,
+        } => {
+            let Some(list) = read_list() else {
+                return ExitCode::FAILURE;
+            };
+
+            export(&list, name.as_deref(), *format, output.as_ref()).await
+        }
+        CollectionsCommand::Import { file, sync } => import(file, *sync).await,
+        command => modify(command).await,
+    }
+}
+
+fn open_store() -> Option<CollectionStore> {
+    let config = config::get();
+
+    match CollectionStore::open(
+        &config.osu.collection_path,
+        config.user.collection_backups,
+        config.user.when_osu_running,
+        false,
+    ) {
+        Ok(store) => Some(store),
+        Err(error) => {
+            error!("collection.db couldn't be read: {}", error);
+            None
+        }
+    }
+}
+
+/// Rename, delete, merge, dedupe and prune, through the same store as the downloader
+/// (backups, deferred writes while osu! is running).
+async fn modify(command: &CollectionsCommand) -> ExitCode {
+    let Some(store) = open_store() else {
+        return ExitCode::FAILURE;
+    };
+    let names = store.names().await;
+
+    let missing = match command {
+        CollectionsCommand::Rename { name, .. } => vec![name],
+        CollectionsCommand::Delete { names } | CollectionsCommand::Merge { names, .. } => {
+            names.iter().collect()
+        }
+        CollectionsCommand::Dedupe { name } | CollectionsCommand::Prune { name } => {
+            name.iter().collect()
+        }
+        _ => vec![],
+    }
+    .into_iter()
+    .filter(|name| !names.contains(name))
+    .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        for name in missing {
+            error!("there's no collection called {}", name);
+        }
+
+        return ExitCode::FAILURE;
+    }
+
+    match command {
+        CollectionsCommand::Rename { name, new_name } => {
+            if names.contains(new_name) {
+                error!("there's already a collection called {}", new_name);
+                return ExitCode::FAILURE;
+            }
+
+            store
+                .apply(CollectionChange::Rename {
+                    name: name.clone(),
+                    new_name: new_name.clone(),
+                })
+                .await;
+            info!("{} is renamed to {}", name, new_name);
+        }
+        CollectionsCommand::Delete { names } => {
+            for name in names {
+                store
+                    .apply(CollectionChange::Delete { name: name.clone() })
+                    .await;
+                info!("{} is deleted", name);
+            }
+        }
+        CollectionsCommand::Merge { names, into, keep } => {
+            create_collection(&store, into).await;
+
+            let mut added = 0;
+            for name in names.iter().filter(|name| *name != into) {
+                for checksum in store.hashes(name).await {
+                    if add_to_collection(&store, into, &checksum).await {
+                        added += 1;
+                    }
+                }
+
+                if !keep {
+                    store
+                        .apply(CollectionChange::Delete { name: name.clone() })
+                        .await;
+                }
+            }
+
+            info!("{} beatmaps are merged into {}", added, into);
+        }
+        CollectionsCommand::Dedupe { name } => {
+            for name in name
+                .as_ref()
+                .map_or(names.clone(), |name| vec![name.clone()])
+            {
+                let count = store.hashes(&name).await.len();
+                store
+                    .apply(CollectionChange::Dedupe { name: name.clone() })
+                    .await;
+
+                let removed = count - store.hashes(&name).await.len();
+                if removed > 0 {
+                    info!("{}: {} duplicates removed", name, removed);
+                }
+            }
+        }
+        CollectionsCommand::Prune { name } => {
+            let library = match load_library(&config::get().osu.osu_db_path).await {
+                Ok(library) => library,
+                Err(error) => {
+                    error!("osu!.db couldn't be read: {}", error);
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            for name in name
+                .as_ref()
+                .map_or(names.clone(), |name| vec![name.clone()])
+            {
+                let mut removed = 0;
+                for checksum in store.hashes(&name).await {
+                    if !library.beatmaps.contains_key(&checksum)
+                        && remove_from_collection(&store, &name, &checksum).await
+                    {
+                        removed += 1;
+                    }
+                }
+
+                if removed > 0 {
+                    info!(
+                        "{}: {} beatmaps osu! doesn't have are removed",
+                        name, removed
+                    );
+                }
+            }
+        }
+        CollectionsCommand::List
+        | CollectionsCommand::Export { .. }
+        | CollectionsCommand::Import { .. } => unreachable!(),
+    }
+
+    if let Err(error) = store.flush().await {
+        error!("collection.db couldn't be written: {}", error);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn read_list() -> Option<CollectionList> {
+    match CollectionList::from_file(&config::get().osu.collection_path) {
+        Ok(list) => Some(list),
+        Err(error) => {
+            error!("collection.db couldn't be read: {}", error);
+            None
+        }
+    }
+}
+
+fn select<'a>(list: &'a CollectionList, name: Option<&str>) -> Vec<&'a Collection> {
+    list.collections
+        .iter()
+        .filter(|c| name.is_none_or(|name| c.name.as_deref() == Some(name)))
+        .collect()
+}
+
+/// Beatmaps osu! knows are exported with their IDs (so the recipient can download them),
+/// the others only by their hash.
+async fn export_osdb(
+    collections: Vec<&Collection>,
+    name: Option<&str>,
+    output: Option<&PathBuf>,
+) -> ExitCode {
+    let library = load_library_or_warn().await;
+
+    let collections = collections
+        .into_iter()
+        .map(|collection| {
+            let mut exported = OsdbCollection {
+                name: collection.name.clone().unwrap_or_default(),
+                online_id: 0,
+                beatmaps: vec![],
+                hashes: vec![],
+            };
+
+            for checksum in collection.beatmap_hashes.iter().flatten() {
+                match library.as_ref().and_then(|l| l.beatmaps.get(checksum)) {
+                    Some(beatmap) => exported.beatmaps.push(OsdbBeatmap {
+                        beatmap_id: beatmap.beatmap_id,
+                        beatmapset_id: beatmap.beatmapset_id,
+                        artist: beatmap.artist.clone(),
+                        title: beatmap.title.clone(),
+                        version: beatmap.version.clone(),
+                        checksum: checksum.clone(),
+                        comment: String::new(),
+                        mode: beatmap.mode,
+                        stars: beatmap.stars,
+                    }),
+                    None => exported.hashes.push(checksum.clone()),
+                }
+            }
+
+            exported
+        })
+        .collect::<Vec<_>>();
+
+    let output = output.cloned().unwrap_or_else(|| {
+        PathBuf::from(format!("{}.osdb", sanitise(name.unwrap_or("collections"))))
+    });
+
+    let result = {
+        let output = output.clone();
+        tokio::task::spawn_blocking(move || osdb::write(&output, &collections))
+            .await
+            .expect("osdb export task panicked")
+    };
+
+    match result {
+        Ok(_) => {
+            info!("exported to {}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("{} couldn't be written: {}", output.display(), error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn load_library_or_warn() -> Option<Library> {
+    match load_library(&config::get().osu.osu_db_path).await {
+        Ok(library) => Some(library),
+        Err(error) => {
+            warn!(
+                "osu!.db couldn't be read ({}), beatmaps are exported by their hash only.",
+                error
+            );
+            None
+        }
+    }
+}
+
+async fn export(
+    list: &CollectionList,
+    name: Option<&str>,
+    format: ExportFormat,
+    output: Option<&PathBuf>,
+) -> ExitCode {
+    let collections = select(list, name);
+
+    if let Some(name) = name
+        && collections.is_empty()
+    {
+        error!("there's no collection called {}", name);
+        return ExitCode::FAILURE;
+    }
+
+    let contents = match format {
+        ExportFormat::Text => to_text(&collections, name.is_none()),
+        ExportFormat::Json => {
+            let library = load_library_or_warn().await;
+            let exported = resolve(&collections, library.as_ref());
+
+            serde_json::to_string_pretty(&exported).unwrap() + "\n"
+        }
+        ExportFormat::Csv => {
+            let library = load_library_or_warn().await;
+            to_csv(&resolve(&collections, library.as_ref()))
+        }
+        ExportFormat::Osdb => return export_osdb(collections, name, output).await,
+    };
+
+    let Some(output) = output else {
+        print!("{}", contents);
+        return ExitCode::SUCCESS;
+    };
+
+    match tokio::fs::write(output, contents).await {
+        Ok(_) => {
+            info!(
+                "{} collections exported to {}",
+                collections.len(),
+                output.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("{} couldn't be written: {}", output.display(), error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `# <name>` followed by the hashes of each collection (only the hashes, with `headers` unset).
+fn to_text(collections: &[&Collection], headers: bool) -> String {
+    let mut contents = String::new();
+
+    for collection in collections {
+        if headers {
+            let _ = writeln!(
+                contents,
+                "# {}",
+                collection.name.as_deref().unwrap_or_default()
+            );
+        }
+
+        for checksum in collection.beatmap_hashes.iter().flatten() {
+            let _ = writeln!(contents, "{}", checksum);
+        }
+    }
+
+    contents
+}
+
+fn resolve(collections: &[&Collection], library: Option<&Library>) -> Vec<ExportedCollection> {
+    collections
+        .iter()
+        .map(|collection| ExportedCollection {
+            name: collection.name.clone().unwrap_or_default(),
+            beatmaps: collection
+                .beatmap_hashes
+                .iter()
+                .flatten()
+                .map(|checksum| {
+                    let beatmap = library.and_then(|l| l.beatmaps.get(checksum));
+
+                    ExportedBeatmap {
+                        checksum: checksum.clone(),
+                        beatmapset_id: beatmap.map(|b| b.beatmapset_id),
+                        beatmap_id: beatmap.map(|b| b.beatmap_id),
+                        artist: beatmap.map(|b| b.artist.clone()),
+                        title: beatmap.map(|b| b.title.clone()),
+                        version: beatmap.map(|b| b.version.clone()),
+                    }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn to_csv(collections: &[ExportedCollection]) -> String {
+    let mut contents =
+        String::from("collection,checksum,beatmapset_id,beatmap_id,artist,title,version\n");
+
+    for collection in collections {
+        for beatmap in &collection.beatmaps {
+            let fields = [
+                collection.name.clone(),
+                beatmap.checksum.clone(),
+                beatmap
+                    .beatmapset_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                beatmap
+                    .beatmap_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                beatmap.artist.clone().unwrap_or_default(),
+                beatmap.title.clone().unwrap_or_default(),
+                beatmap.version.clone().unwrap_or_default(),
+            ];
+
+            let _ = writeln!(
+                contents,
+                "{}",
+                fields
+                    .iter()
+                    .map(|f| csv_field(f))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+        }
+    }
+
+    contents
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Applies a JSON export through the same store as the downloader (backups, deferred writes).
+async fn import(path: &PathBuf, sync: bool) -> ExitCode {
+    let config = config::get();
+
+    let collections = match tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            serde_json::from_str::<Vec<ExportedCollection>>(&contents).map_err(|e| e.to_string())
+        }) {
+        Ok(collections) => collections,
+        Err(error) => {
+            error!("{} couldn't be read: {}", path.display(), error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let Some(store) = open_store() else {
+        return ExitCode::FAILURE;
+    };
+
+    // only to describe what --sync removes
+    let library = if sync {
+        load_library(&config.osu.osu_db_path).await.ok()
+    } else {
+        None
+    };
+
+    for collection in &collections {
+        create_collection(&store, &collection.name).await;
+
+        if sync {
+            sync_collection(
+                &store,
+                &collection.name,
+                &collection
+                    .beatmaps
+                    .iter()
+                    .map(|b| b.checksum.as_str())
+                    .collect(),
+                None,
+                library.as_ref(),
+            )
+            .await;
+        }
+
+        let mut added = 0;
+        for beatmap in &collection.beatmaps {
+            if add_to_collection(&store, &collection.name, &beatmap.checksum).await {
+                added += 1;
+            }
+        }
+
+        info!("{}: {} beatmaps added", collection.name, added);
+    }
+
+    if let Err(error) = store.flush().await {
+        error!("collection.db couldn't be written: {}", error);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,42 @@
+use std::{fs, path::Path, process::ExitCode};
+
+use clap::Subcommand;
+use tracing::{error, info};
+
+const EXAMPLE: &str = include_str!("../../config.toml.example");
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Create config.toml from the documented example
+    Init {
+        /// Overwrite an existing config.toml
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+pub fn run(command: &ConfigCommand) -> ExitCode {
+    match command {
+        ConfigCommand::Init { force } => init(*force),
+    }
+}
+
+fn init(force: bool) -> ExitCode {
+    let path = Path::new("config.toml");
+
+    if path.exists() && !force {
+        error!("config.toml already exists, pass --force to overwrite it.");
+        return ExitCode::FAILURE;
+    }
+
+    match fs::write(path, EXAMPLE) {
+        Ok(_) => {
+            info!("config.toml created, set your collection ID in [collector] and you're ready.");
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("config.toml couldn't be written: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
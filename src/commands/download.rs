@@ -0,0 +1,765 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use futures::StreamExt;
+use sanitise_file_name::sanitise;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{error, info, warn};
+
+use crate::{
+    collector::{
+        self, Beatmap, Beatmapset, CollectionBeatmaps, CollectionInfoBeatmap,
+        CollectionInfoBeatmapsets, Gamemode,
+    },
+    config::{self, FilterConfig},
+    mirrors::{self, Mirror, MirrorError},
+    utilities::{
+        collection::{
+            CollectionChange, CollectionStore, add_to_collection, create_collection,
+            format_collection_name, format_tournament_name, sync_collection,
+        },
+        osdb::{self, OsdbCollection},
+        osu::{index_songs, load_library},
+    },
+};
+
+/// Beatmapset ID and the name of the mirror that served it, or the last mirror's error.
+type DownloadReport = Vec<(i32, Result<&'static str, MirrorError>)>;
+
+#[derive(clap::Args, Debug)]
+pub struct DownloadArgs {
+    /// osu!collector collection IDs or URLs (defaults to `collector.id` from config.toml)
+    #[arg(value_parser = collector::parse_id)]
+    pub collections: Vec<i32>,
+
+    /// osu!collector tournament IDs or URLs, downloaded into one collection per round
+    #[arg(long = "tournament", value_parser = collector::parse_tournament_id)]
+    pub tournaments: Vec<i32>,
+
+    /// Collection Manager's .osdb files, each of their collections is merged as it's named
+    #[arg(long = "osdb")]
+    pub osdb_files: Vec<PathBuf>,
+
+    /// Make the local collections match the remote ones exactly, removing beatmaps that aren't in them anymore
+    #[arg(long)]
+    pub sync: bool,
+
+    /// Report what would be downloaded and changed, without downloading or writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub filter: FilterConfig,
+}
+
+/// A local collection to fill, from an osu!collector collection, a tournament round, or
+/// a collection of an .osdb file.
+struct Source {
+    name: String,
+    /// With `--sync`, where the beatmaps that aren't in the remote collection anymore go.
+    local_only_name: Option<String>,
+    beatmapsets: Vec<CollectionInfoBeatmapsets>,
+    /// Beatmaps without a known beatmapset, added to the collection but never downloaded.
+    hashes: Vec<String>,
+}
+
+/// Turns the collections of an .osdb file into sources, their metadata going into `metadata`.
+fn osdb_sources(
+    collections: Vec<OsdbCollection>,
+    metadata: &mut CollectionBeatmaps,
+) -> Vec<Source> {
+    collections
+        .into_iter()
+        .map(|collection| {
+            let mut source = Source {
+                name: collection.name,
+                local_only_name: None,
+                beatmapsets: vec![],
+                hashes: collection.hashes,
+            };
+
+            for beatmap in collection.beatmaps {
+                if beatmap.beatmapset_id <= 0 {
+                    source.hashes.push(beatmap.checksum);
+                    continue;
+                }
+
+                metadata.beatmaps.push(Beatmap {
+                    checksum: beatmap.checksum.clone(),
+                    version: beatmap.version,
+                    mode: Gamemode::from_raw(beatmap.mode),
+                    status: None,
+                    difficulty_rating: Some(beatmap.stars as f32).filter(|stars| *stars > 0.0),
+                    bpm: None,
+                    total_length: None,
+                    ar: None,
+                    accuracy: None,
+                    cs: None,
+                    drain: None,
+                });
+                // sets exported without their metadata (the "min" variants) are named by ID
+                if !beatmap.artist.is_empty() || !beatmap.title.is_empty() {
+                    metadata.beatmapsets.push(Beatmapset {
+                        id: beatmap.beatmapset_id,
+                        artist: beatmap.artist,
+                        title: beatmap.title,
+                        status: None,
+                    });
+                }
+
+                let checksum = CollectionInfoBeatmap {
+                    checksum: beatmap.checksum,
+                };
+                match source
+                    .beatmapsets
+                    .iter_mut()
+                    .find(|s| s.id == beatmap.beatmapset_id)
+                {
+                    Some(beatmapset) => beatmapset.beatmaps.push(checksum),
+                    None => source.beatmapsets.push(CollectionInfoBeatmapsets {
+                        id: beatmap.beatmapset_id,
+                        beatmaps: vec![checksum],
+                    }),
+                }
+            }
+
+            source
+        })
+        .collect()
+}
+
+/// A beatmapset to download (once), with the difficulties each local collection wants from it.
+struct QueuedBeatmapset {
+    id: i32,
+    beatmaps: Vec<CollectionInfoBeatmap>,
+    collections: Vec<(Arc<String>, Vec<String>)>,
+}
+
+async fn add_to_collections(store: &CollectionStore, collections: &[(Arc<String>, Vec<String>)]) {
+    for (name, checksums) in collections {
+        for checksum in checksums {
+            add_to_collection(store, name, checksum).await;
+        }
+    }
+}
+
+/// Drops the difficulties excluded by `[filter]`, and the beatmapsets left without any.
+fn apply_filter(
+    filter: &FilterConfig,
+    beatmapsets: &mut Vec<CollectionInfoBeatmapsets>,
+    metadata: &CollectionBeatmaps,
+) {
+    let beatmaps = metadata
+        .beatmaps
+        .iter()
+        .map(|b| (b.checksum.as_str(), b))
+        .collect::<HashMap<_, _>>();
+
+    for beatmapset in beatmapsets.iter_mut() {
+        let beatmapset_entity = metadata.beatmapsets.iter().find(|s| s.id == beatmapset.id);
+
+        beatmapset.beatmaps.retain(|beatmap| {
+            beatmaps
+                .get(beatmap.checksum.as_str())
+                .is_none_or(|b| filter.matches(b, beatmapset_entity))
+        });
+    }
+
+    beatmapsets.retain(|s| !s.beatmaps.is_empty());
+}
+
+/// Downloads the beatmapsets of osu!collector collections and tournaments (each set only once,
+/// even when it's in several of them), and merges each into its own collection in `collection.db`.
+pub async fn run(args: &DownloadArgs) -> ExitCode {
+    let config = config::get();
+    let mirrors = Arc::new(
+        config
+            .mirrors
+            .iter()
+            .map(|m| m.get_mirror())
+            .collect::<Vec<_>>(),
+    );
+
+    if config.collector.ids.is_empty()
+        && config.collector.tournament_ids.is_empty()
+        && args.osdb_files.is_empty()
+    {
+        error!(
+            "nothing to download, pass a collection (or --tournament) ID or URL, an --osdb file, or set `id` in [collector]."
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let mut sources = vec![];
+    // "<uploader> - <name>" of every collection and tournament, for the summary
+    let mut titles = vec![];
+    let mut remote_collection_beatmaps = CollectionBeatmaps {
+        beatmaps: vec![],
+        beatmapsets: vec![],
+    };
+
+    for id in &config.collector.ids {
+        let info = match collector::get_info(*id).await {
+            Ok(info) => info,
+            Err(error) => {
+                error!(
+                    "collection {} couldn't be fetched from osu!collector: {}",
+                    id, error
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut beatmaps = match collector::get_beatmaps(*id).await {
+            Ok(beatmaps) => beatmaps,
+            Err(error) => {
+                error!(
+                    "beatmaps of collection {} couldn't be fetched from osu!collector: {}",
+                    id, error
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!(
+            "{} by {} (with {} beatmaps)",
+            info.name.trim(),
+            info.uploader.username,
+            info.beatmap_count
+        );
+
+        remote_collection_beatmaps
+            .beatmaps
+            .append(&mut beatmaps.beatmaps);
+        remote_collection_beatmaps
+            .beatmapsets
+            .append(&mut beatmaps.beatmapsets);
+
+        titles.push(format!("{} - {}", info.uploader.username, info.name));
+        sources.push(Source {
+            name: format_collection_name(&config.user.collection_name_format, &info),
+            local_only_name: config
+                .user
+                .sync_local_collection_format
+                .as_ref()
+                .map(|fmt| format_collection_name(fmt, &info)),
+            beatmapsets: info.beatmapsets,
+            hashes: vec![],
+        });
+    }
+
+    for id in &config.collector.tournament_ids {
+        let tournament = match collector::get_tournament(*id).await {
+            Ok(tournament) => tournament,
+            Err(error) => {
+                error!(
+                    "tournament {} couldn't be fetched from osu!collector: {}",
+                    id, error
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!(
+            "{} by {} (with {} rounds)",
+            tournament.name.trim(),
+            tournament.uploader.username,
+            tournament.rounds.len()
+        );
+
+        let mut beatmaps = tournament.beatmaps();
+        remote_collection_beatmaps
+            .beatmaps
+            .append(&mut beatmaps.beatmaps);
+        remote_collection_beatmaps
+            .beatmapsets
+            .append(&mut beatmaps.beatmapsets);
+
+        titles.push(format!(
+            "{} - {}",
+            tournament.uploader.username, tournament.name
+        ));
+        for round in &tournament.rounds {
+            sources.push(Source {
+                name: format_tournament_name(
+                    &config.user.tournament_name_format,
+                    &tournament,
+                    round,
+                ),
+                local_only_name: None,
+                beatmapsets: round.beatmapsets(),
+                hashes: vec![],
+            });
+        }
+    }
+
+    for path in &args.osdb_files {
+        let result = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || osdb::read(&path))
+                .await
+                .expect("osdb import task panicked")
+        };
+
+        let collections = match result {
+            Ok(collections) => collections,
+            Err(error) => {
+                error!("{} couldn't be read: {}", path.display(), error);
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!(
+            "{} (with {} collections)",
+            path.display(),
+            collections.len()
+        );
+
+        titles.push(path.display().to_string());
+        sources.append(&mut osdb_sources(
+            collections,
+            &mut remote_collection_beatmaps,
+        ));
+    }
+
+    for source in &mut sources {
+        let count = source.beatmapsets.len();
+        apply_filter(
+            &config.filter,
+            &mut source.beatmapsets,
+            &remote_collection_beatmaps,
+        );
+
+        if source.beatmapsets.len() != count {
+            info!(
+                "{}: {} out of {} beatmapsets are left out by [filter]",
+                source.name,
+                count - source.beatmapsets.len(),
+                count
+            );
+        }
+    }
+
+    let remote_collection_beatmaps = Arc::new(remote_collection_beatmaps);
+
+    let collection_store = match CollectionStore::open(
+        &config.osu.collection_path,
+        config.user.collection_backups,
+        config.user.when_osu_running,
+        args.dry_run,
+    ) {
+        Ok(store) => Arc::new(store),
+        Err(error) => {
+            error!("collection.db couldn't be read: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let library = match load_library(&config.osu.osu_db_path).await {
+        Ok(library) => Some(library),
+        Err(error) => {
+            warn!(
+                "osu!.db couldn't be read ({}), looking for installed beatmaps in the Songs folder instead.",
+                error
+            );
+            None
+        }
+    };
+
+    let mut queue: Vec<QueuedBeatmapset> = vec![];
+    let mut queue_indices = HashMap::new();
+
+    for source in &sources {
+        let local_collection_name = Arc::new(source.name.clone());
+
+        create_collection(&collection_store, &local_collection_name).await;
+
+        if args.sync {
+            sync_collection(
+                &collection_store,
+                &local_collection_name,
+                &source
+                    .beatmapsets
+                    .iter()
+                    .flat_map(|s| s.beatmaps.iter().map(|b| b.checksum.as_str()))
+                    .chain(source.hashes.iter().map(String::as_str))
+                    .collect(),
+                source.local_only_name.as_deref(),
+                library.as_ref(),
+            )
+            .await;
+        }
+
+        for checksum in &source.hashes {
+            add_to_collection(&collection_store, &local_collection_name, checksum).await;
+        }
+
+        for beatmapset in &source.beatmapsets {
+            let index = *queue_indices.entry(beatmapset.id).or_insert_with(|| {
+                queue.push(QueuedBeatmapset {
+                    id: beatmapset.id,
+                    beatmaps: vec![],
+                    collections: vec![],
+                });
+                queue.len() - 1
+            });
+            let queued = &mut queue[index];
+
+            for beatmap in &beatmapset.beatmaps {
+                if !queued
+                    .beatmaps
+                    .iter()
+                    .any(|b| b.checksum == beatmap.checksum)
+                {
+                    queued.beatmaps.push(beatmap.clone());
+                }
+            }
+
+            queued.collections.push((
+                Arc::clone(&local_collection_name),
+                beatmapset
+                    .beatmaps
+                    .iter()
+                    .map(|b| b.checksum.clone())
+                    .collect(),
+            ));
+        }
+    }
+
+    let processed = Arc::new(AtomicUsize::new(0));
+    let beatmapset_count = queue.len();
+
+    let semaphore = Arc::new(Semaphore::new(config.user.concurrent_downloads));
+    let mut tasks = JoinSet::new();
+    // to know which beatmapset a panicked task was downloading
+    let mut task_beatmapsets = HashMap::new();
+    // with --dry-run, beatmapsets that would be downloaded, and how many of them are outdated
+    let mut planned = vec![];
+    let mut outdated = 0;
+    let songs = index_songs(&config.osu.songs_path).await;
+
+    for beatmapset in queue {
+        let checksums = beatmapset
+            .beatmaps
+            .iter()
+            .map(|b| b.checksum.as_str())
+            .collect::<Vec<_>>();
+        let local_entry = songs.get(&beatmapset.id);
+
+        let is_pending = local_entry.is_some_and(|p| p.extension().is_some_and(|e| e == "osz"));
+
+        let (is_installed, missing) = match &library {
+            Some(library) if !is_pending && library.contains(beatmapset.id, &checksums) => {
+                (true, library.missing(&checksums))
+            }
+            // archives from a previous run, that osu! didn't import yet
+            Some(_) => (is_pending, vec![]),
+            None => (local_entry.is_some(), vec![]),
+        };
+
+        if !missing.is_empty() {
+            let versions = missing
+                .iter()
+                .map(|checksum| {
+                    remote_collection_beatmaps
+                        .beatmaps
+                        .iter()
+                        .find(|b| b.checksum == *checksum)
+                        .map(|b| format!("[{}]", b.version))
+                        .unwrap_or(checksum.to_string())
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            if config.user.redownload_outdated {
+                info!(
+                    "{} is outdated locally, downloading it again: {}",
+                    beatmapset.id, versions
+                );
+            } else {
+                warn!(
+                    "{} is outdated locally, these difficulties won't resolve in the collection: {}",
+                    beatmapset.id, versions
+                );
+            }
+        }
+
+        if is_installed && (missing.is_empty() || !config.user.redownload_outdated) {
+            add_to_collections(&collection_store, &beatmapset.collections).await;
+
+            processed.fetch_add(1, Ordering::SeqCst);
+            continue;
+        }
+
+        if args.dry_run {
+            // so the plan shows the collection changes a download would make
+            add_to_collections(&collection_store, &beatmapset.collections).await;
+
+            planned.push(beatmapset.id);
+            outdated += usize::from(is_installed);
+            continue;
+        }
+
+        let collection_store = Arc::clone(&collection_store);
+        let mirrors = Arc::clone(&mirrors);
+        let processed = Arc::clone(&processed);
+        let remote_collection_beatmaps = Arc::clone(&remote_collection_beatmaps);
+        let semaphore = Arc::clone(&semaphore);
+        let beatmapset_id = beatmapset.id;
+
+        let task = tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+
+            // metadata can be missing from osu!collector, the set is then only known by its ID
+            let beatmapset_name = remote_collection_beatmaps
+                .beatmapsets
+                .iter()
+                .find(|s| s.id == beatmapset.id)
+                .map(|s| format!("{} - {}", s.artist, s.title));
+
+            let file_name = match &beatmapset_name {
+                Some(name) => format!("{} {}", beatmapset.id, name),
+                None => beatmapset.id.to_string(),
+            };
+            let file_path = PathBuf::from(format!(
+                "{}/{}.osz",
+                config.osu.songs_path,
+                sanitise(file_name.as_str())
+            ));
+
+            let checksums = if config.user.verify_checksums {
+                beatmapset
+                    .beatmaps
+                    .iter()
+                    .map(|b| b.checksum.clone())
+                    .collect::<Vec<_>>()
+            } else {
+                vec![]
+            };
+
+            let mut result = Err(MirrorError::NotFound);
+            for (index, mirror) in mirrors.iter().enumerate() {
+                let download = mirrors::with_retry(**mirror, beatmapset.id, &config.retry, || {
+                    mirror.download(beatmapset.id, &file_path, &checksums, config.user.no_video)
+                });
+
+                match download.await {
+                    Ok(_) => {
+                        result = Ok(mirror.get_name());
+                        break;
+                    }
+                    Err(error) => {
+                        match mirrors.get(index + 1) {
+                            Some(next) => warn!(
+                                "{} ({}): {}, falling back to {}",
+                                mirror.get_name(),
+                                beatmapset.id,
+                                error,
+                                next.get_name()
+                            ),
+                            None => error!("{} ({}): {}", mirror.get_name(), beatmapset.id, error),
+                        }
+
+                        result = Err(error);
+                    }
+                }
+            }
+
+            let progress = processed.fetch_add(1, Ordering::SeqCst) + 1;
+
+            if result.is_ok() {
+                // leftovers from mirrors that failed before the one that succeeded
+                for mirror in mirrors.iter() {
+                    let _ = tokio::fs::remove_file(
+                        mirror.get_part_path(&file_path, config.user.no_video),
+                    )
+                    .await;
+                }
+
+                add_to_collections(&collection_store, &beatmapset.collections).await;
+
+                for beatmap in &beatmapset.beatmaps {
+                    let version = remote_collection_beatmaps
+                        .beatmaps
+                        .iter()
+                        .find(|b| b.checksum == beatmap.checksum)
+                        .map(|b| b.version.as_str())
+                        .unwrap_or(beatmap.checksum.as_str());
+
+                    info!(
+                        "({}/{}) {} [{}]",
+                        progress,
+                        beatmapset_count,
+                        beatmapset_name.as_deref().unwrap_or(&file_name),
+                        version
+                    );
+                }
+            }
+
+            drop(_permit);
+            (beatmapset.id, result)
+        });
+
+        task_beatmapsets.insert(task.id(), beatmapset_id);
+    }
+
+    if args.dry_run {
+        print_plan(
+            *mirrors[0],
+            &planned,
+            beatmapset_count,
+            outdated,
+            &collection_store.changes(),
+        )
+        .await;
+
+        return ExitCode::SUCCESS;
+    }
+
+    let mut report = DownloadReport::new();
+    let mut panicked = 0;
+
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(entry) => report.push(entry),
+            Err(error) => {
+                panicked += 1;
+                error!(
+                    "{} <- download task failed ({})",
+                    task_beatmapsets
+                        .get(&error.id())
+                        .copied()
+                        .unwrap_or_default(),
+                    error
+                );
+            }
+        }
+    }
+
+    let written = match collection_store.flush().await {
+        Ok(()) => true,
+        Err(error) => {
+            error!("collection.db couldn't be written: {}", error);
+            false
+        }
+    };
+
+    for (id, result) in report.iter() {
+        let name = remote_collection_beatmaps
+            .beatmapsets
+            .iter()
+            .find(|s| s.id == *id)
+            .map(|s| format!("{} - {}", s.artist, s.title))
+            .unwrap_or_default();
+
+        match result {
+            Ok(mirror) => info!("{} {} <- {}", id, name, mirror),
+            Err(MirrorError::NotFound) => error!("{} {} <- not found on any mirror", id, name),
+            Err(error) => error!("{} {} <- failed on every mirror ({})", id, name, error),
+        }
+    }
+
+    // the downloads are still reported, they're in the Songs folder either way
+    if !written {
+        return ExitCode::FAILURE;
+    }
+
+    let names = titles.join(", ");
+
+    let failed = panicked + report.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        error!(
+            "{} merged, but {} out of {} beatmapsets couldn't be downloaded.",
+            names, failed, beatmapset_count
+        );
+
+        return ExitCode::FAILURE;
+    }
+
+    info!("{} downloaded/merged, have fun!", names);
+
+    ExitCode::SUCCESS
+}
+
+/// What a run would do: downloads (with their estimated size) and `collection.db` changes.
+async fn print_plan(
+    mirror: &(dyn Mirror + Sync),
+    planned: &[i32],
+    beatmapset_count: usize,
+    outdated: usize,
+    changes: &[CollectionChange],
+) {
+    let config = config::get();
+
+    info!(
+        "{} out of {} beatmapsets are installed, {} would be downloaded ({} of them outdated)",
+        beatmapset_count - planned.len(),
+        beatmapset_count,
+        planned.len(),
+        outdated
+    );
+
+    if !planned.is_empty() {
+        let sizes = futures::stream::iter(planned)
+            .map(|id| mirror.get_size(*id, config.user.no_video))
+            .buffer_unordered(config.user.concurrent_downloads)
+            .collect::<Vec<_>>()
+            .await;
+
+        let total = sizes.iter().flatten().flatten().sum::<u64>();
+        let unknown = sizes.iter().filter(|s| !matches!(s, Ok(Some(_)))).count();
+
+        info!(
+            "estimated download size from {}: {:.1} MiB{}",
+            mirror.get_name(),
+            total as f64 / (1024.0 * 1024.0),
+            match unknown {
+                0 => String::new(),
+                _ => format!(" (unknown for {} beatmapsets)", unknown),
+            }
+        );
+    }
+
+    // (name, is created, added, removed), in order of appearance
+    let mut collections: Vec<(&str, bool, usize, usize)> = vec![];
+    for change in changes {
+        let (name, created, added, removed) = match change {
+            CollectionChange::Create { name } => (name, true, 0, 0),
+            CollectionChange::Add { name, .. } => (name, false, 1, 0),
+            CollectionChange::Remove { name, .. } => (name, false, 0, 1),
+            // not made by downloads, only by `collections` commands deferred while osu! was running
+            CollectionChange::Rename { name, .. }
+            | CollectionChange::Delete { name }
+            | CollectionChange::Dedupe { name } => (name, false, 0, 0),
+        };
+
+        match collections.iter_mut().find(|c| c.0 == name) {
+            Some(entry) => {
+                entry.1 |= created;
+                entry.2 += added;
+                entry.3 += removed;
+            }
+            None => collections.push((name, created, added, removed)),
+        }
+    }
+
+    if collections.is_empty() {
+        info!("collection.db wouldn't change");
+    }
+
+    for (name, created, added, removed) in collections {
+        info!(
+            "collection.db: {}{} (+{} / -{} beatmaps)",
+            name,
+            if created { " [new]" } else { "" },
+            added,
+            removed
+        );
+    }
+}
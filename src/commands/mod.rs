@@ -0,0 +1,4 @@
+pub mod collections;
+pub mod config;
+pub mod download;
+pub mod verify;
@@ -0,0 +1,76 @@
+use std::process::ExitCode;
+
+use osu_db::CollectionList;
+use tracing::{error, info, warn};
+
+use crate::{config, utilities::osu::load_library};
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Only check this collection
+    pub name: Option<String>,
+}
+
+/// Reports the beatmaps of the local collections that osu! doesn't have, i.e. the ones
+/// showing up as missing in-game.
+pub async fn run(args: &VerifyArgs) -> ExitCode {
+    let config = config::get();
+
+    let library = match load_library(&config.osu.osu_db_path).await {
+        Ok(library) => library,
+        Err(error) => {
+            error!("osu!.db couldn't be read: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let list = match CollectionList::from_file(&config.osu.collection_path) {
+        Ok(list) => list,
+        Err(error) => {
+            error!("collection.db couldn't be read: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut missing_count = 0;
+    for collection in &list.collections {
+        let name = collection.name.as_deref().unwrap_or_default();
+        if args.name.as_deref().is_some_and(|n| n != name) {
+            continue;
+        }
+
+        let missing = collection
+            .beatmap_hashes
+            .iter()
+            .flatten()
+            .filter(|checksum| !library.beatmaps.contains_key(*checksum))
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            info!(
+                "{}: all {} beatmaps are installed",
+                name,
+                collection.beatmap_hashes.len()
+            );
+            continue;
+        }
+
+        warn!(
+            "{}: {} out of {} beatmaps are missing",
+            name,
+            missing.len(),
+            collection.beatmap_hashes.len()
+        );
+        for checksum in &missing {
+            warn!("- {}", checksum);
+        }
+
+        missing_count += missing.len();
+    }
+
+    if missing_count > 0 {
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
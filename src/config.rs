@@ -1,10 +1,16 @@
-use std::fs;
+use std::{fs, sync::OnceLock, time::Duration};
 
-use serde::{Deserialize, de};
+use serde::{Deserialize, Serialize, de};
 
 use crate::{
+    collector::{self, Beatmap, BeatmapStatus, Beatmapset, Gamemode},
     mirrors::{
-        Mirror, beatconnect::Beatconnect, catboy::Catboy, nerinyan::Nerinyan, osudirect::OsuDirect,
+        Mirror,
+        beatconnect::Beatconnect,
+        catboy::Catboy,
+        custom::{Custom, CustomMirrorConfig},
+        nerinyan::Nerinyan,
+        osudirect::OsuDirect,
         sayobot::Sayobot,
     },
     utilities::osu,
@@ -16,16 +22,20 @@ pub enum MirrorType {
     Nerinyan(Nerinyan),
     Beatconnect(Beatconnect),
     Sayobot(Sayobot),
+    Custom(Box<Custom>),
 }
 
 impl MirrorType {
-    pub const ALL: [MirrorType; 5] = [
-        MirrorType::Catboy(Catboy),
-        MirrorType::OsuDirect(OsuDirect),
-        MirrorType::Nerinyan(Nerinyan),
-        MirrorType::Beatconnect(Beatconnect),
-        MirrorType::Sayobot(Sayobot),
-    ];
+    /// Every built-in mirror, each with a fresh rate-limit state.
+    pub fn all() -> [MirrorType; 5] {
+        [
+            MirrorType::Catboy(Catboy::default()),
+            MirrorType::OsuDirect(OsuDirect::default()),
+            MirrorType::Nerinyan(Nerinyan::default()),
+            MirrorType::Beatconnect(Beatconnect::default()),
+            MirrorType::Sayobot(Sayobot::default()),
+        ]
+    }
 
     #[allow(clippy::redundant_allocation)]
     pub fn get_mirror(&self) -> Box<&(dyn Mirror + Sync)> {
@@ -35,60 +45,449 @@ impl MirrorType {
             MirrorType::Nerinyan(m) => Box::new(m),
             MirrorType::Beatconnect(m) => Box::new(m),
             MirrorType::Sayobot(m) => Box::new(m),
+            MirrorType::Custom(m) => Box::new(m.as_ref()),
         }
     }
-}
 
-impl<'de> Deserialize<'de> for MirrorType {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        match s.as_str() {
-            "catboy" => Ok(MirrorType::Catboy(Catboy)),
-            "osudirect" => Ok(MirrorType::OsuDirect(OsuDirect)),
-            "nerinyan" => Ok(MirrorType::Nerinyan(Nerinyan)),
-            "beatconnect" => Ok(MirrorType::Beatconnect(Beatconnect)),
-            "sayobot" => Ok(MirrorType::Sayobot(Sayobot)),
-            _ => Err(de::Error::unknown_variant(
-                &s,
-                &["catboy", "osudirect", "nerinyan", "beatconnect", "sayobot"],
+    /// Resolves a `mirror_type` entry, custom mirrors taking precedence over built-in ones.
+    pub fn from_name(name: &str, custom_mirrors: &[CustomMirrorConfig]) -> Result<Self, String> {
+        if let Some(config) = custom_mirrors.iter().find(|m| m.name == name) {
+            return Custom::new(config.clone()).map(|m| MirrorType::Custom(Box::new(m)));
+        }
+
+        match name {
+            "catboy" => Ok(MirrorType::Catboy(Catboy::default())),
+            "osudirect" => Ok(MirrorType::OsuDirect(OsuDirect::default())),
+            "nerinyan" => Ok(MirrorType::Nerinyan(Nerinyan::default())),
+            "beatconnect" => Ok(MirrorType::Beatconnect(Beatconnect::default())),
+            "sayobot" => Ok(MirrorType::Sayobot(Sayobot::default())),
+            _ => Err(format!(
+                "unknown mirror '{}', expected one of: catboy, osudirect, nerinyan, beatconnect, sayobot{}",
+                name,
+                custom_mirrors
+                    .iter()
+                    .map(|m| format!(", {}", m.name))
+                    .collect::<String>()
             )),
         }
     }
 }
 
+/// Accepts either a single mirror (`"catboy"`) or an ordered fallback chain
+/// (`["catboy", "osudirect"]`).
+fn deserialize_mirror_chain<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MirrorChain {
+        Single(String),
+        Chain(Vec<String>),
+    }
+
+    let mirrors = match MirrorChain::deserialize(deserializer)? {
+        MirrorChain::Single(mirror) => vec![mirror],
+        MirrorChain::Chain(mirrors) => mirrors,
+    };
+
+    if mirrors.is_empty() {
+        return Err(de::Error::invalid_length(0, &"at least one mirror"));
+    }
+
+    Ok(mirrors)
+}
+
 //
 #[derive(Deserialize)]
 pub struct UserConfig {
-    pub mirror_type: MirrorType,
+    #[serde(deserialize_with = "deserialize_mirror_chain")]
+    pub mirror_type: Vec<String>,
     pub collection_name_format: String,
     pub concurrent_downloads: usize,
+    #[serde(default = "default_verify_checksums")]
+    pub verify_checksums: bool,
+    #[serde(default)]
+    pub no_video: bool,
+    #[serde(default)]
+    pub redownload_outdated: bool,
+    #[serde(default = "default_collection_backups")]
+    pub collection_backups: usize,
+    #[serde(default)]
+    pub when_osu_running: GameRunningAction,
+    /// With `--sync`, where the beatmaps removed from the remote collection go
+    /// (same placeholders as `collection_name_format`), instead of being dropped.
+    #[serde(default)]
+    pub sync_local_collection_format: Option<String>,
+    /// Name of the local collection created for each round of a tournament.
+    #[serde(default = "default_tournament_name_format")]
+    pub tournament_name_format: String,
+}
+
+/// What to do with collection changes when osu! is running (it'd overwrite them on exit).
+#[derive(Clone, Copy, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GameRunningAction {
+    /// Wait until the game is closed.
+    #[default]
+    Wait,
+    /// Save them into a pending file, applied on the next run.
+    Defer,
+}
+
+fn default_verify_checksums() -> bool {
+    true
+}
+
+fn default_collection_backups() -> usize {
+    5
+}
+
+fn default_tournament_name_format() -> String {
+    "{tournament} - {round}".to_string()
+}
+
+fn deserialize_collections<'de, D>(deserializer: D) -> Result<Vec<i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_ids(deserializer, collector::parse_id)
+}
+
+fn deserialize_tournaments<'de, D>(deserializer: D) -> Result<Vec<i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_ids(deserializer, collector::parse_tournament_id)
+}
+
+/// Accepts a single ID (or URL, resolved by `parse`) or a list of them, duplicates and 0 are dropped.
+fn deserialize_ids<'de, D>(
+    deserializer: D,
+    parse: fn(&str) -> Result<i32, String>,
+) -> Result<Vec<i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum CollectionRef {
+        Id(i32),
+        Url(String),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Collections {
+        Single(CollectionRef),
+        List(Vec<CollectionRef>),
+    }
+
+    let collections = match Collections::deserialize(deserializer)? {
+        Collections::Single(collection) => vec![collection],
+        Collections::List(collections) => collections,
+    };
+
+    let mut ids = vec![];
+    for collection in collections {
+        let id = match collection {
+            CollectionRef::Id(id) => id,
+            CollectionRef::Url(url) => parse(&url).map_err(de::Error::custom)?,
+        };
+
+        // 0 is the example's placeholder, as good as no ID
+        if id != 0 && !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+
+    Ok(ids)
 }
 
 #[derive(Deserialize)]
 pub struct CollectorConfig {
-    pub id: i32,
+    /// osu!collector collections to download, `id` in config.toml.
+    #[serde(rename = "id", default, deserialize_with = "deserialize_collections")]
+    pub ids: Vec<i32>,
+    /// osu!collector tournaments to download, `tournament` in config.toml.
+    #[serde(
+        rename = "tournament",
+        default,
+        deserialize_with = "deserialize_tournaments"
+    )]
+    pub tournament_ids: Vec<i32>,
+}
+
+/// Which beatmaps of the remote collections are downloaded and added, everything by default.
+/// Beatmaps osu!collector has no information about are kept.
+#[derive(clap::Args, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FilterConfig {
+    /// Only keep these game modes (e.g. osu,mania)
+    #[arg(long, value_delimiter = ',')]
+    pub mode: Option<Vec<Gamemode>>,
+
+    /// Only keep these ranked statuses (e.g. ranked,loved)
+    #[arg(long, value_delimiter = ',')]
+    pub status: Option<Vec<BeatmapStatus>>,
+
+    #[arg(long)]
+    pub min_stars: Option<f32>,
+    #[arg(long)]
+    pub max_stars: Option<f32>,
+
+    /// In seconds
+    #[arg(long)]
+    pub min_length: Option<u32>,
+    /// In seconds
+    #[arg(long)]
+    pub max_length: Option<u32>,
+
+    #[arg(long)]
+    pub min_bpm: Option<f32>,
+    #[arg(long)]
+    pub max_bpm: Option<f32>,
+
+    #[arg(long)]
+    pub min_ar: Option<f32>,
+    #[arg(long)]
+    pub max_ar: Option<f32>,
+
+    #[arg(long)]
+    pub min_od: Option<f32>,
+    #[arg(long)]
+    pub max_od: Option<f32>,
+
+    #[arg(long)]
+    pub min_cs: Option<f32>,
+    #[arg(long)]
+    pub max_cs: Option<f32>,
+
+    #[arg(long)]
+    pub min_hp: Option<f32>,
+    #[arg(long)]
+    pub max_hp: Option<f32>,
+}
+
+impl FilterConfig {
+    pub fn matches(&self, beatmap: &Beatmap, beatmapset: Option<&Beatmapset>) -> bool {
+        let status = beatmap.status.or(beatmapset.and_then(|s| s.status));
+
+        is_one_of(beatmap.mode, &self.mode)
+            && is_one_of(status, &self.status)
+            && is_within(beatmap.difficulty_rating, self.min_stars, self.max_stars)
+            && is_within(beatmap.total_length, self.min_length, self.max_length)
+            && is_within(beatmap.bpm, self.min_bpm, self.max_bpm)
+            && is_within(beatmap.ar, self.min_ar, self.max_ar)
+            && is_within(beatmap.accuracy, self.min_od, self.max_od)
+            && is_within(beatmap.cs, self.min_cs, self.max_cs)
+            && is_within(beatmap.drain, self.min_hp, self.max_hp)
+    }
+}
+
+fn is_one_of<T: PartialEq>(value: Option<T>, allowed: &Option<Vec<T>>) -> bool {
+    match (value, allowed) {
+        (Some(value), Some(allowed)) => allowed.contains(&value),
+        _ => true,
+    }
+}
+
+fn is_within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    let Some(value) = value else {
+        return true;
+    };
+
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 3,
+            base_delay_ms: 1000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Exponential backoff for the given attempt (starting at 0), with up to 50% of jitter
+    /// so parallel downloads don't hammer a recovering mirror at the same moment.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.max_delay_ms);
+        let jitter = rand::random_range(0..=delay / 2);
+
+        Duration::from_millis(delay / 2 + jitter)
+    }
 }
 
 #[derive(Default, Deserialize)]
 pub struct OsuConfig {
     pub songs_path: String,
     pub collection_path: String,
+    pub osu_db_path: String,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
     pub user: UserConfig,
     pub collector: CollectorConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub custom_mirrors: Vec<CustomMirrorConfig>,
     #[serde(skip_deserializing)]
     pub osu: OsuConfig,
+    /// `user.mirror_type`, resolved into actual mirrors.
+    #[serde(skip_deserializing)]
+    pub mirrors: Vec<MirrorType>,
 }
 
-pub fn init() -> Config {
-    let contents = fs::read_to_string("config.toml").expect("config.toml doesn't exist!");
-    let mut config = toml::from_str::<Config>(&contents).unwrap();
+/// Command-line overrides of `config.toml`, one per `[user]`, `[collector]` and `[filter]` field.
+#[derive(clap::Args, Default)]
+pub struct Overrides {
+    /// Mirrors to download from, in order of preference (e.g. catboy,osudirect)
+    #[arg(long = "mirror", value_delimiter = ',', global = true)]
+    pub mirror_type: Option<Vec<String>>,
+
+    /// Name of the local collection ({collection_author}, {collection_title}, {collection_id})
+    #[arg(long, global = true)]
+    pub collection_name_format: Option<String>,
+
+    /// How many beatmapsets are downloaded at the same time (up to 6)
+    #[arg(long, global = true)]
+    pub concurrent_downloads: Option<usize>,
+
+    /// Check downloaded archives against the collection's checksums (`=false` turns it off)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", global = true)]
+    pub verify_checksums: Option<bool>,
+
+    /// Download beatmapsets without their video, when the mirror supports it (`=false` turns it off)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", global = true)]
+    pub no_video: Option<bool>,
+
+    /// Download beatmapsets again when the local copy is an older revision (`=false` turns it off)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", global = true)]
+    pub redownload_outdated: Option<bool>,
+
+    /// How many backups of collection.db are kept
+    #[arg(long, global = true)]
+    pub collection_backups: Option<usize>,
+
+    /// What to do with collection changes while osu! is running
+    #[arg(long, global = true)]
+    pub when_osu_running: Option<GameRunningAction>,
+
+    /// With --sync, collection receiving the beatmaps removed from the remote one
+    #[arg(long, global = true)]
+    pub sync_local_collection_format: Option<String>,
+
+    /// Name of the local collections of tournament rounds ({tournament}, {tournament_id}, {round})
+    #[arg(long, global = true)]
+    pub tournament_name_format: Option<String>,
+
+    /// osu!collector collections (given to `download`)
+    #[arg(skip)]
+    pub collector_ids: Option<Vec<i32>>,
+
+    /// osu!collector tournaments (given to `download --tournament`)
+    #[arg(skip)]
+    pub tournament_ids: Option<Vec<i32>>,
+
+    /// Given to `download`, like the collections
+    #[arg(skip)]
+    pub filter: FilterConfig,
+}
+
+impl Overrides {
+    fn apply(&self, config: &mut toml::Table) {
+        let user = section(config, "user");
+        set(user, "mirror_type", &self.mirror_type);
+        set(user, "collection_name_format", &self.collection_name_format);
+        set(user, "concurrent_downloads", &self.concurrent_downloads);
+        set(user, "verify_checksums", &self.verify_checksums);
+        set(user, "no_video", &self.no_video);
+        set(user, "redownload_outdated", &self.redownload_outdated);
+        set(user, "collection_backups", &self.collection_backups);
+        set(user, "when_osu_running", &self.when_osu_running);
+        set(
+            user,
+            "sync_local_collection_format",
+            &self.sync_local_collection_format,
+        );
+        set(user, "tournament_name_format", &self.tournament_name_format);
+
+        let collector = section(config, "collector");
+        set(collector, "id", &self.collector_ids);
+        set(collector, "tournament", &self.tournament_ids);
+
+        // unset filters are left out when serialized
+        if let toml::Value::Table(filter) = toml::Value::try_from(&self.filter).unwrap() {
+            section(config, "filter").extend(filter);
+        }
+    }
+}
+
+fn section<'a>(config: &'a mut toml::Table, name: &str) -> &'a mut toml::Table {
+    let value = config
+        .entry(name)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+
+    if !value.is_table() {
+        *value = toml::Value::Table(toml::Table::new());
+    }
+
+    value.as_table_mut().unwrap()
+}
+
+fn set<T: Serialize>(table: &mut toml::Table, key: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        table.insert(key.to_string(), toml::Value::try_from(value).unwrap());
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The loaded configuration, [`init`] has to be called first.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config wasn't loaded")
+}
+
+/// Loads `config.toml` (which can be left out, if `overrides` cover the required fields).
+pub fn init(overrides: &Overrides) -> &'static Config {
+    CONFIG.get_or_init(|| load(overrides))
+}
+
+fn load(overrides: &Overrides) -> Config {
+    let contents = fs::read_to_string("config.toml").unwrap_or_default();
+    let mut table = toml::from_str::<toml::Table>(&contents).unwrap_or_else(|e| {
+        panic!(
+            "config.toml is missing or invalid (`ecstasy config init` creates one): {}",
+            e
+        )
+    });
+    overrides.apply(&mut table);
+
+    let mut config = toml::Value::Table(table)
+        .try_into::<Config>()
+        .unwrap_or_else(|e| {
+            panic!(
+                "config.toml is missing or invalid (`ecstasy config init` creates one): {}",
+                e
+            )
+        });
 
     if config.user.concurrent_downloads > 6 {
         panic!(
@@ -96,9 +495,18 @@ pub fn init() -> Config {
         );
     }
 
+    config.mirrors = config
+        .user
+        .mirror_type
+        .iter()
+        .map(|name| MirrorType::from_name(name, &config.custom_mirrors))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("{}", e));
+
     let osu_path = osu::find_game().unwrap();
     config.osu.songs_path = format!("{}\\Songs", osu_path);
     config.osu.collection_path = format!("{}\\collection.db", osu_path);
+    config.osu.osu_db_path = format!("{}\\osu!.db", osu_path);
 
     config
 }
@@ -1,48 +1,31 @@
-use serde::Deserialize;
+use reqwest::{StatusCode, header::HeaderMap};
 
-use super::{Mirror, Ratelimiter};
+use super::{Mirror, MirrorError, Ratelimiter};
 
-#[derive(Deserialize)]
-pub struct Beatconnect;
+#[derive(Default)]
+pub struct Beatconnect {
+    rate_limiter: Ratelimiter,
+}
 
 #[async_trait::async_trait]
 impl Mirror for Beatconnect {
-    fn get_name(&self) -> &'static str {
+    fn get_name(&self) -> &str {
         "beatconnect.io"
     }
 
-    fn get_base_url(&self) -> &'static str {
+    fn get_base_url(&self) -> &str {
         "https://beatconnect.io/b"
     }
 
-    async fn get_file(&self, id: i32, rate_limit: &Ratelimiter) -> Result<Vec<u8>, String> {
-        rate_limit.wait_if_needed().await;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .get(format!("{}/{}", self.get_base_url(), id))
-            .header("User-Agent", "shockpast/ecstasy: 1.1.2")
-            .send()
-            .await
-            .unwrap();
-
-        rate_limit.update_rate_limit(response.headers()).await;
-
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
-            .unwrap_or_default();
-
-        let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    fn get_rate_limiter(&self) -> &Ratelimiter {
+        &self.rate_limiter
+    }
 
-        if content_type.contains("application/json") {
-            if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&bytes) {
-                return Err(json.to_string());
-            }
-        }
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        self.rate_limiter.update_retry_after(headers).await;
+    }
 
-        Ok(bytes.to_vec())
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        MirrorError::from_json_response(status, headers, body)
     }
 }
@@ -1,53 +1,64 @@
+use reqwest::{StatusCode, header::HeaderMap};
 use serde::Deserialize;
 
-use super::{Mirror, Ratelimiter};
+use super::{Mirror, MirrorError, RATELIMIT_WINDOW, Ratelimiter, header_value};
 
 #[derive(Debug, Clone, Deserialize)]
 struct ErrorResponse {
     error: String,
 }
 
-#[derive(Deserialize)]
-pub struct Catboy;
+#[derive(Default)]
+pub struct Catboy {
+    rate_limiter: Ratelimiter,
+}
 
 #[async_trait::async_trait]
 impl Mirror for Catboy {
-    fn get_name(&self) -> &'static str {
+    fn get_name(&self) -> &str {
         "catboy.best"
     }
 
-    fn get_base_url(&self) -> &'static str {
+    fn get_base_url(&self) -> &str {
         "https://catboy.best/d"
     }
 
-    async fn get_file(&self, id: i32, rate_limit: &Ratelimiter) -> Result<Vec<u8>, String> {
-        rate_limit.wait_if_needed().await;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .get(format!("{}/{}", self.get_base_url(), id))
-            .header("User-Agent", "shockpast/ecstasy: 1.1.2")
-            .send()
-            .await
-            .unwrap();
+    fn supports_no_video(&self) -> bool {
+        true
+    }
 
-        rate_limit.update_rate_limit(response.headers()).await;
+    fn get_download_url(&self, id: i32, no_video: bool) -> String {
+        if no_video {
+            format!("{}/{}n", self.get_base_url(), id)
+        } else {
+            format!("{}/{}", self.get_base_url(), id)
+        }
+    }
 
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
-            .unwrap_or_default();
+    fn get_rate_limiter(&self) -> &Ratelimiter {
+        &self.rate_limiter
+    }
 
-        let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        // files served straight from s3 are much more expensive for catboy,
+        // and the limit drops way faster than the header says.
+        if header_value::<String>(headers, "content-origin").as_deref() == Some("s3")
+            && self.rate_limiter.info.read().await.remaining <= 5
+        {
+            self.rate_limiter.limit_for(RATELIMIT_WINDOW).await;
+            return;
+        }
 
-        if content_type.contains("application/json") {
-            if let Ok(json) = serde_json::from_slice::<ErrorResponse>(&bytes) {
-                return Err(json.error);
-            }
+        if let Some(remaining) = header_value(headers, "x-ratelimit-remaining") {
+            self.rate_limiter.update_remaining(remaining, 1, None).await;
         }
+    }
+
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        let message = serde_json::from_slice::<ErrorResponse>(body)
+            .ok()
+            .map(|json| json.error);
 
-        Ok(bytes.to_vec())
+        MirrorError::from_response(status, headers, message)
     }
 }
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::{
+    StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+use sanitise_file_name::sanitise;
+use serde::Deserialize;
+
+use super::{Mirror, MirrorError, Ratelimiter, header_value};
+
+/// A mirror declared in `config.toml` under `[[custom_mirrors]]`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CustomMirrorConfig {
+    pub name: String,
+    /// e.g. `https://cache.local/d/{id}`
+    pub url: String,
+    /// Same as `url`, but for archives without video.
+    pub no_video_url: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Dot-separated path to the message in JSON error bodies (e.g. `error` or `detail.message`).
+    pub error_path: Option<String>,
+    pub ratelimit_remaining_header: Option<String>,
+    /// Header holding the amount of seconds until the rate-limit resets.
+    pub ratelimit_reset_header: Option<String>,
+}
+
+pub struct Custom {
+    config: CustomMirrorConfig,
+    headers: HeaderMap,
+    rate_limiter: Ratelimiter,
+}
+
+impl Custom {
+    pub fn new(config: CustomMirrorConfig) -> Result<Custom, String> {
+        // the name ends up in the `.part` file names
+        if config.name.is_empty() || sanitise(&config.name) != config.name {
+            return Err(format!(
+                "'{}' isn't a valid mirror name, it must be usable in a file name",
+                config.name
+            ));
+        }
+
+        if !config.url.contains("{id}") {
+            return Err(format!("{}: 'url' must contain {{id}}", config.name));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("{}: '{}' {}", config.name, name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("{}: '{}' {}", config.name, name, e))?;
+
+            headers.insert(name, value);
+        }
+
+        Ok(Custom {
+            config,
+            headers,
+            rate_limiter: Ratelimiter::default(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Mirror for Custom {
+    fn get_name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn get_base_url(&self) -> &str {
+        &self.config.url
+    }
+
+    fn get_rate_limiter(&self) -> &Ratelimiter {
+        &self.rate_limiter
+    }
+
+    fn get_headers(&self) -> HeaderMap {
+        self.headers.clone()
+    }
+
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        if let Some(remaining_header) = &self.config.ratelimit_remaining_header
+            && let Some(remaining) = header_value(headers, remaining_header)
+        {
+            let reset_in = self
+                .config
+                .ratelimit_reset_header
+                .as_ref()
+                .and_then(|reset_header| header_value(headers, reset_header))
+                .map(Duration::from_secs);
+
+            self.rate_limiter
+                .update_remaining(remaining, 1, reset_in)
+                .await;
+        }
+
+        self.rate_limiter.update_retry_after(headers).await;
+    }
+
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        let message = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .map(|json| {
+                let pointer = self
+                    .config
+                    .error_path
+                    .as_ref()
+                    .map(|path| format!("/{}", path.replace('.', "/")))
+                    .unwrap_or_default();
+
+                match json.pointer(&pointer) {
+                    Some(serde_json::Value::String(message)) => message.clone(),
+                    Some(value) => value.to_string(),
+                    None => json.to_string(),
+                }
+            });
+
+        MirrorError::from_response(status, headers, message)
+    }
+
+    fn supports_no_video(&self) -> bool {
+        self.config.no_video_url.is_some()
+    }
+
+    fn get_download_url(&self, id: i32, no_video: bool) -> String {
+        let url = match &self.config.no_video_url {
+            Some(no_video_url) if no_video => no_video_url,
+            _ => &self.config.url,
+        };
+
+        url.replace("{id}", &id.to_string())
+    }
+}
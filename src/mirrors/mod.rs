@@ -1,83 +1,466 @@
 use std::{
-    sync::Arc,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
     time::{Duration, Instant},
 };
 
-use reqwest::header::HeaderMap;
-use tokio::sync::RwLock;
-use tracing::info;
+use reqwest::{
+    Response, StatusCode,
+    header::{HeaderMap, RANGE},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::RwLock,
+};
+use tracing::{info, warn};
+
+use crate::{config::RetryConfig, utilities::archive};
 
 pub mod beatconnect;
 pub mod catboy;
+pub mod custom;
 pub mod nerinyan;
 pub mod osudirect;
 pub mod sayobot;
 
+/// How long a mirror is avoided once it reports that the rate-limit is exhausted,
+/// unless it tells us exactly when the limit resets.
+const RATELIMIT_WINDOW: Duration = Duration::from_secs(60);
+
 #[derive(Default)]
 pub struct RatelimitInfo {
     pub remaining: u32,
     pub reset_at: Option<Instant>,
 }
 
+/// Rate-limit state of a single mirror, every `Mirror` owns one.
 #[derive(Default)]
 pub struct Ratelimiter {
-    pub info: Arc<RwLock<RatelimitInfo>>,
+    pub info: RwLock<RatelimitInfo>,
 }
 
 impl Ratelimiter {
-    async fn wait_if_needed(&self) {
-        if let Some(reset_at) = self.info.read().await.reset_at {
-            if reset_at > Instant::now() {
-                let wait_duration = reset_at.duration_since(Instant::now());
+    pub async fn wait_if_needed(&self) {
+        let reset_at = self.info.read().await.reset_at;
 
-                info!(
-                    "You've hit an rate-limit, chill out, and wait until you can send requests again."
-                );
-                info!(
-                    "{} seconds left, until rate-limit will reset.",
-                    wait_duration.as_secs() % 60
-                );
+        if let Some(reset_at) = reset_at
+            && reset_at > Instant::now()
+        {
+            let wait_duration = reset_at.duration_since(Instant::now());
 
-                tokio::time::sleep(wait_duration).await;
-            }
+            info!(
+                "You've hit an rate-limit, chill out, and wait until you can send requests again."
+            );
+            info!(
+                "{} seconds left, until rate-limit will reset.",
+                wait_duration.as_secs()
+            );
+
+            tokio::time::sleep(wait_duration).await;
         }
     }
 
-    async fn update_rate_limit(&self, headers: &HeaderMap) {
-        if let Some(origin) = headers.get("content-origin").and_then(|v| v.to_str().ok()) {
-            if origin == "s3" && self.info.read().await.remaining <= 5 {
-                let now = Instant::now();
-                let seconds_in_next_minute = 60 - now.elapsed().as_secs() % 60;
-                let now = now + Duration::from_secs(seconds_in_next_minute);
+    /// Blocks every following request for `duration`.
+    pub async fn limit_for(&self, duration: Duration) {
+        let mut info = self.info.write().await;
+
+        info.remaining = 0;
+        info.reset_at = Some(Instant::now() + duration);
+    }
 
-                self.info.write().await.remaining = 0;
-                self.info.write().await.reset_at = Some(now);
+    /// Stores the remaining amount of requests, and blocks the mirror for the
+    /// rest of the window once it drops to `threshold`.
+    pub async fn update_remaining(
+        &self,
+        remaining: u32,
+        threshold: u32,
+        reset_in: Option<Duration>,
+    ) {
+        self.info.write().await.remaining = remaining;
 
-                return;
-            }
+        if remaining <= threshold {
+            self.limit_for(reset_in.unwrap_or(RATELIMIT_WINDOW)).await;
+        }
+    }
+
+    /// Honors a `Retry-After` header (in seconds), if the mirror sent one.
+    pub async fn update_retry_after(&self, headers: &HeaderMap) {
+        if let Some(seconds) = header_value::<u64>(headers, "retry-after") {
+            self.limit_for(Duration::from_secs(seconds)).await;
         }
+    }
+}
 
-        if let Some(remaining) = headers
-            .get("x-ratelimit-remaining")
-            .and_then(|v| v.to_str().ok())
-        {
-            self.info.write().await.remaining = remaining.parse().unwrap_or(0);
+pub fn header_value<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+pub const USER_AGENT: &str = "shockpast/ecstasy: 1.1.2";
+
+#[derive(Debug)]
+pub enum MirrorError {
+    /// The mirror doesn't have this beatmapset.
+    NotFound,
+    /// The mirror asked us to slow down, `retry_after` is set when it told for how long.
+    RateLimited { retry_after: Option<Duration> },
+    /// The mirror failed on its side (5xx).
+    ServerError(StatusCode),
+    /// The mirror answered, but not with a beatmapset archive (HTML page, truncated body...).
+    InvalidPayload(String),
+    /// The request never completed (DNS, connection reset, timeout...).
+    Network(String),
+    /// The mirror refused the request for any other reason.
+    Rejected(String),
+    /// The archive couldn't be written into the Songs folder.
+    Io(io::Error),
+}
+
+impl MirrorError {
+    /// Maps an error response, `message` being the explanation the mirror put into
+    /// its body (if any).
+    pub fn from_response(
+        status: StatusCode,
+        headers: &HeaderMap,
+        message: Option<String>,
+    ) -> MirrorError {
+        match status {
+            StatusCode::NOT_FOUND => MirrorError::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => MirrorError::RateLimited {
+                retry_after: header_value(headers, "retry-after").map(Duration::from_secs),
+            },
+            StatusCode::REQUEST_TIMEOUT => MirrorError::Network(status.to_string()),
+            status if status.is_server_error() => MirrorError::ServerError(status),
+            _ => match message {
+                Some(message) if message.to_lowercase().contains("not found") => {
+                    MirrorError::NotFound
+                }
+                Some(message) => MirrorError::Rejected(message),
+                None => MirrorError::Rejected(status.to_string()),
+            },
+        }
+    }
+
+    /// [`MirrorError::from_response`], for mirrors explaining errors with a JSON body
+    /// holding an `error` or `message` string.
+    pub fn from_json_response(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        let message = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .map(|json| match json.get("error").or(json.get("message")) {
+                Some(serde_json::Value::String(message)) => message.clone(),
+                _ => json.to_string(),
+            });
+
+        MirrorError::from_response(status, headers, message)
+    }
+
+    /// Whether the same request might succeed if it's sent again a bit later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            MirrorError::RateLimited { .. } | MirrorError::ServerError(_) | MirrorError::Network(_)
+        )
+    }
+}
+
+impl From<reqwest::Error> for MirrorError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            MirrorError::InvalidPayload(error.to_string())
+        } else {
+            MirrorError::Network(error.to_string())
         }
+    }
+}
 
-        if self.info.read().await.remaining <= 1 {
-            let now = Instant::now();
-            let seconds_in_next_minute = 60 - now.elapsed().as_secs() % 60;
-            let now = now + Duration::from_secs(seconds_in_next_minute);
+impl From<io::Error> for MirrorError {
+    fn from(error: io::Error) -> Self {
+        MirrorError::Io(error)
+    }
+}
 
-            self.info.write().await.remaining = 0;
-            self.info.write().await.reset_at = Some(now);
+impl fmt::Display for MirrorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MirrorError::NotFound => f.write_str("beatmapset not found"),
+            MirrorError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "rate-limited for {}s", retry_after.as_secs()),
+            MirrorError::RateLimited { retry_after: None } => f.write_str("rate-limited"),
+            MirrorError::ServerError(status) => write!(f, "server error ({})", status),
+            MirrorError::InvalidPayload(message) => write!(f, "invalid payload ({})", message),
+            MirrorError::Network(message) => write!(f, "network error ({})", message),
+            MirrorError::Rejected(message) => f.write_str(message),
+            MirrorError::Io(error) => write!(f, "couldn't write the archive ({})", error),
         }
     }
 }
 
 #[async_trait::async_trait]
 pub trait Mirror {
-    fn get_name(&self) -> &'static str;
-    fn get_base_url(&self) -> &'static str;
-    async fn get_file(&self, id: i32, rate_limiter: &Ratelimiter) -> Result<Vec<u8>, String>;
+    fn get_name(&self) -> &str;
+    fn get_base_url(&self) -> &str;
+    fn get_rate_limiter(&self) -> &Ratelimiter;
+
+    /// Extra headers sent along with every request.
+    fn get_headers(&self) -> HeaderMap {
+        HeaderMap::new()
+    }
+    /// Updates the mirror's own rate-limit state from the headers of a response.
+    async fn update_rate_limit(&self, headers: &HeaderMap);
+    /// Maps an error response (or a successful one with a JSON body) into a `MirrorError`,
+    /// every mirror describes its errors differently.
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError;
+
+    /// Whether the mirror can serve beatmapsets with the video stripped out.
+    fn supports_no_video(&self) -> bool {
+        false
+    }
+
+    /// URL of the beatmapset archive, `no_video` is only honored by mirrors that support it.
+    fn get_download_url(&self, id: i32, _no_video: bool) -> String {
+        format!("{}/{}", self.get_base_url(), id)
+    }
+
+    /// `.part` files are kept per mirror (and per variant), as different mirrors don't
+    /// necessarily serve byte-identical archives.
+    fn get_part_path(&self, path: &Path, no_video: bool) -> PathBuf {
+        let variant = if no_video && self.supports_no_video() {
+            ".novideo"
+        } else {
+            ""
+        };
+
+        let mut part_path = path.as_os_str().to_owned();
+        part_path.push(format!(".{}{}.part", self.get_name(), variant));
+
+        PathBuf::from(part_path)
+    }
+
+    /// Sends the download request (starting at byte `offset` when it's not 0), and turns
+    /// every response that isn't a beatmapset archive into an error.
+    async fn request(&self, url: &str, offset: u64) -> Result<Response, MirrorError> {
+        self.get_rate_limiter().wait_if_needed().await;
+
+        let client = reqwest::Client::new();
+        let mut request = client
+            .get(url)
+            .header("User-Agent", USER_AGENT)
+            .headers(self.get_headers());
+
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        let response = request.send().await?;
+
+        self.update_rate_limit(response.headers()).await;
+
+        let status = response.status();
+        let content_type =
+            header_value::<String>(response.headers(), "content-type").unwrap_or_default();
+
+        if !status.is_success() || content_type.contains("application/json") {
+            let headers = response.headers().clone();
+            let body = response.bytes().await?;
+
+            return Err(self.map_error(status, &headers, &body));
+        }
+
+        if content_type.contains("text/") {
+            return Err(MirrorError::InvalidPayload(format!(
+                "received {} instead of an archive",
+                content_type
+            )));
+        }
+
+        Ok(response)
+    }
+
+    async fn get_file(&self, id: i32) -> Result<Vec<u8>, MirrorError> {
+        let response = self.request(&self.get_download_url(id, false), 0).await?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Size of the archive as advertised by a `HEAD` request, if the mirror sends one.
+    async fn get_size(&self, id: i32, no_video: bool) -> Result<Option<u64>, MirrorError> {
+        self.get_rate_limiter().wait_if_needed().await;
+
+        let response = reqwest::Client::new()
+            .head(self.get_download_url(id, no_video))
+            .header("User-Agent", USER_AGENT)
+            .headers(self.get_headers())
+            .send()
+            .await?;
+
+        self.update_rate_limit(response.headers()).await;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(self.map_error(status, response.headers(), &[]));
+        }
+
+        Ok(header_value::<u64>(response.headers(), "content-length"))
+    }
+
+    /// Streams the beatmapset into `path` through a `.part` file next to it, so a
+    /// half-downloaded archive never shows up as an `.osz` that osu! would try to import.
+    ///
+    /// When the mirror advertises `Accept-Ranges`, the `.part` file is kept after a
+    /// transient failure, and the next call resumes from where it stopped.
+    ///
+    /// The archive is validated before it's moved into place, `checksums` being the
+    /// difficulties it has to contain. Returns the size of the archive.
+    async fn download(
+        &self,
+        id: i32,
+        path: &Path,
+        checksums: &[String],
+        no_video: bool,
+    ) -> Result<u64, MirrorError> {
+        let url = self.get_download_url(id, no_video && self.supports_no_video());
+        let part_path = self.get_part_path(path, no_video);
+        let offset = tokio::fs::metadata(&part_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let response = match self.request(&url, offset).await {
+            // the mirror didn't like our range (e.g. the part file is stale), start over
+            Err(error) if offset > 0 && !error.is_transient() => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                self.request(&url, 0).await?
+            }
+            result => result?,
+        };
+
+        let is_resumed = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+        let accepts_ranges = is_resumed
+            || header_value::<String>(response.headers(), "accept-ranges").as_deref()
+                == Some("bytes");
+
+        if is_resumed {
+            info!(
+                "{} ({}): resuming from {} bytes",
+                self.get_name(),
+                id,
+                offset
+            );
+        } else if offset > 0 {
+            info!(
+                "{} ({}): mirror ignored the range, downloading from scratch",
+                self.get_name(),
+                id
+            );
+        }
+
+        let result =
+            match write_part(response, &part_path, if is_resumed { offset } else { 0 }).await {
+                Ok(size) => validate_part(&part_path, checksums).await.map(|_| size),
+                Err(error) => Err(error),
+            };
+
+        match result {
+            Ok(size) => {
+                tokio::fs::rename(&part_path, path).await?;
+                Ok(size)
+            }
+            Err(error) => {
+                if !accepts_ranges || !error.is_transient() {
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                }
+
+                Err(error)
+            }
+        }
+    }
+}
+
+async fn validate_part(part_path: &Path, checksums: &[String]) -> Result<(), MirrorError> {
+    let part_path = part_path.to_owned();
+    let checksums = checksums.to_vec();
+
+    tokio::task::spawn_blocking(move || archive::validate(&part_path, &checksums))
+        .await
+        .map_err(|e| MirrorError::Io(io::Error::other(e)))?
+        .map_err(MirrorError::InvalidPayload)
+}
+
+/// Writes the response body into `part_path`, appending to it when `offset` isn't 0.
+async fn write_part(
+    mut response: Response,
+    part_path: &Path,
+    offset: u64,
+) -> Result<u64, MirrorError> {
+    let expected_size = response.content_length().map(|length| offset + length);
+    let mut file = match offset {
+        0 => File::create(part_path).await?,
+        _ => OpenOptions::new().append(true).open(part_path).await?,
+    };
+    let mut size = offset;
+
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+
+    file.sync_all().await?;
+
+    if let Some(expected_size) = expected_size
+        && expected_size != size
+    {
+        return Err(MirrorError::InvalidPayload(format!(
+            "received {} out of {} bytes",
+            size, expected_size
+        )));
+    }
+
+    Ok(size)
+}
+
+/// Runs `request` against `mirror`, retrying transient errors with exponential backoff
+/// and jitter as configured in `[retry]`.
+pub async fn with_retry<T, F, Fut>(
+    mirror: &(dyn Mirror + Sync),
+    id: i32,
+    retry: &RetryConfig,
+    mut request: F,
+) -> Result<T, MirrorError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, MirrorError>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match request().await {
+            Err(error) if error.is_transient() && attempt < retry.max_retries => {
+                let delay = match error {
+                    MirrorError::RateLimited {
+                        retry_after: Some(retry_after),
+                    } => retry_after.max(retry.backoff(attempt)),
+                    _ => retry.backoff(attempt),
+                };
+                attempt += 1;
+
+                warn!(
+                    "{} ({}): {}, retrying in {:.1}s ({}/{})",
+                    mirror.get_name(),
+                    id,
+                    error,
+                    delay.as_secs_f64(),
+                    attempt,
+                    retry.max_retries
+                );
+
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
 }
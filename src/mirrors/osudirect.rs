@@ -1,53 +1,62 @@
+use std::time::Duration;
+
+use reqwest::{StatusCode, header::HeaderMap};
 use serde::Deserialize;
 
-use super::{Mirror, Ratelimiter};
+use super::{Mirror, MirrorError, Ratelimiter, header_value};
 
 #[derive(Debug, Clone, Deserialize)]
 struct ErrorResponse {
     message: String,
 }
 
-#[derive(Deserialize)]
-pub struct OsuDirect;
+#[derive(Default)]
+pub struct OsuDirect {
+    rate_limiter: Ratelimiter,
+}
 
 #[async_trait::async_trait]
 impl Mirror for OsuDirect {
-    fn get_name(&self) -> &'static str {
+    fn get_name(&self) -> &str {
         "osu.direct"
     }
 
-    fn get_base_url(&self) -> &'static str {
+    fn get_base_url(&self) -> &str {
         "https://osu.direct/api/d"
     }
 
-    async fn get_file(&self, id: i32, rate_limit: &Ratelimiter) -> Result<Vec<u8>, String> {
-        rate_limit.wait_if_needed().await;
+    fn supports_no_video(&self) -> bool {
+        true
+    }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .get(format!("{}/{}", self.get_base_url(), id))
-            .header("User-Agent", "shockpast/ecstasy: 1.1.2")
-            .send()
-            .await
-            .unwrap();
+    fn get_download_url(&self, id: i32, no_video: bool) -> String {
+        if no_video {
+            format!("{}/{}?noVideo", self.get_base_url(), id)
+        } else {
+            format!("{}/{}", self.get_base_url(), id)
+        }
+    }
 
-        rate_limit.update_rate_limit(response.headers()).await;
+    fn get_rate_limiter(&self) -> &Ratelimiter {
+        &self.rate_limiter
+    }
 
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
-            .unwrap_or_default();
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        if let Some(remaining) = header_value(headers, "x-ratelimit-remaining") {
+            let reset_in = header_value(headers, "x-ratelimit-reset").map(Duration::from_secs);
+            self.rate_limiter
+                .update_remaining(remaining, 1, reset_in)
+                .await;
+        }
 
-        let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+        self.rate_limiter.update_retry_after(headers).await;
+    }
 
-        if content_type.contains("application/json") {
-            if let Ok(json) = serde_json::from_slice::<ErrorResponse>(&bytes) {
-                return Err(json.message);
-            }
-        }
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        let message = serde_json::from_slice::<ErrorResponse>(body)
+            .ok()
+            .map(|json| json.message);
 
-        Ok(bytes.to_vec())
+        MirrorError::from_response(status, headers, message)
     }
 }
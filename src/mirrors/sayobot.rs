@@ -1,53 +1,41 @@
+use reqwest::{StatusCode, header::HeaderMap};
 use serde::Deserialize;
 
-use super::{Mirror, Ratelimiter};
+use super::{Mirror, MirrorError, Ratelimiter};
 
 #[derive(Debug, Clone, Deserialize)]
 struct ErrorResponse {
     message: String,
 }
 
-#[derive(Deserialize)]
-pub struct Sayobot;
+#[derive(Default)]
+pub struct Sayobot {
+    rate_limiter: Ratelimiter,
+}
 
 #[async_trait::async_trait]
 impl Mirror for Sayobot {
-    fn get_name(&self) -> &'static str {
+    fn get_name(&self) -> &str {
         "sayobot.cn"
     }
 
-    fn get_base_url(&self) -> &'static str {
+    fn get_base_url(&self) -> &str {
         "https://txy1.sayobot.cn/beatmaps/download/full"
     }
 
-    async fn get_file(&self, id: i32, rate_limit: &Ratelimiter) -> Result<Vec<u8>, String> {
-        rate_limit.wait_if_needed().await;
-
-        let client = reqwest::Client::new();
-        let response = client
-            .get(format!("{}/{}", self.get_base_url(), id))
-            .header("User-Agent", "shockpast/ecstasy: 1.1.2")
-            .send()
-            .await
-            .unwrap();
-
-        rate_limit.update_rate_limit(response.headers()).await;
-
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .and_then(|v| v.to_str().ok())
-            .map(|s| s.to_string())
-            .unwrap_or_default();
+    fn get_rate_limiter(&self) -> &Ratelimiter {
+        &self.rate_limiter
+    }
 
-        let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        self.rate_limiter.update_retry_after(headers).await;
+    }
 
-        if content_type.contains("application/json") {
-            if let Ok(json) = serde_json::from_slice::<ErrorResponse>(&bytes) {
-                return Err(json.message);
-            }
-        }
+    fn map_error(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> MirrorError {
+        let message = serde_json::from_slice::<ErrorResponse>(body)
+            .ok()
+            .map(|json| json.message);
 
-        Ok(bytes.to_vec())
+        MirrorError::from_response(status, headers, message)
     }
 }
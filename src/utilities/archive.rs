@@ -0,0 +1,43 @@
+use std::{collections::HashSet, fs::File, io::Read, path::Path};
+
+/// Makes sure that `path` is an actual beatmapset archive: a zip with at least one `.osu`
+/// file, containing every difficulty from `checksums` (MD5 of the `.osu` files).
+pub fn validate(path: &Path, checksums: &[String]) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+
+    let mut hashes = HashSet::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| e.to_string())?;
+        let name = entry.name().map_err(|e| e.to_string())?.to_string();
+        if !name.to_lowercase().ends_with(".osu") {
+            continue;
+        }
+
+        // reading the entry to the end also verifies its CRC
+        let mut contents = vec![];
+        entry
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("{}: {}", name, e))?;
+
+        hashes.insert(format!("{:x}", md5::compute(&contents)));
+    }
+
+    if hashes.is_empty() {
+        return Err("archive doesn't contain any .osu file".to_string());
+    }
+
+    let mismatched = checksums
+        .iter()
+        .filter(|checksum| !hashes.contains(checksum.as_str()))
+        .count();
+    if mismatched > 0 {
+        return Err(format!(
+            "{} out of {} difficulties don't match their checksums",
+            mismatched,
+            checksums.len()
+        ));
+    }
+
+    Ok(())
+}
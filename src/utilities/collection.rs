@@ -1,8 +1,481 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use tokio::sync::RwLock;
+use osu_db::CollectionList;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::{RwLock, mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{error, info, warn};
 
-use crate::collector::Collection;
+use crate::{
+    collector::{Collection, Tournament, TournamentRound},
+    config::GameRunningAction,
+    utilities::osu::{self, Library},
+};
+
+/// How long the writer waits for more changes, before writing `collection.db`.
+const FLUSH_DEBOUNCE: Duration = Duration::from_secs(2);
+/// How often the writer checks whether osu! was closed.
+const GAME_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A single modification of `collection.db`. Changes are kept for the whole run and replayed
+/// onto the file as it is on disk, so collections edited in-game meanwhile aren't lost.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum CollectionChange {
+    Create {
+        name: String,
+    },
+    Add {
+        name: String,
+        checksum: String,
+    },
+    Remove {
+        name: String,
+        checksum: String,
+    },
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    Delete {
+        name: String,
+    },
+    /// Drops the repeated hashes of a collection, keeping their first occurrence.
+    Dedupe {
+        name: String,
+    },
+}
+
+impl CollectionChange {
+    /// Returns whether `list` was modified, changes are idempotent.
+    fn apply(&self, list: &mut CollectionList) -> bool {
+        match self {
+            CollectionChange::Create { name } => {
+                if find_collection(list, name).is_some() {
+                    return false;
+                }
+
+                list.collections.push(osu_db::collection::Collection {
+                    name: Some(name.to_string()),
+                    beatmap_hashes: vec![],
+                });
+
+                true
+            }
+            CollectionChange::Add { name, checksum } => {
+                let Some(collection) = find_collection(list, name) else {
+                    return false;
+                };
+
+                if collection
+                    .beatmap_hashes
+                    .iter()
+                    .any(|c| c.as_deref() == Some(checksum.as_str()))
+                {
+                    return false;
+                }
+
+                collection.beatmap_hashes.push(Some(checksum.to_string()));
+
+                true
+            }
+            CollectionChange::Remove { name, checksum } => {
+                let Some(collection) = find_collection(list, name) else {
+                    return false;
+                };
+
+                let count = collection.beatmap_hashes.len();
+                collection
+                    .beatmap_hashes
+                    .retain(|c| c.as_deref() != Some(checksum.as_str()));
+
+                collection.beatmap_hashes.len() != count
+            }
+            CollectionChange::Rename { name, new_name } => {
+                if find_collection(list, new_name).is_some() {
+                    return false;
+                }
+
+                let Some(collection) = find_collection(list, name) else {
+                    return false;
+                };
+
+                collection.name = Some(new_name.to_string());
+
+                true
+            }
+            CollectionChange::Delete { name } => {
+                let count = list.collections.len();
+                list.collections
+                    .retain(|c| c.name.as_deref().unwrap_or_default() != name);
+
+                list.collections.len() != count
+            }
+            CollectionChange::Dedupe { name } => {
+                let Some(collection) = find_collection(list, name) else {
+                    return false;
+                };
+
+                let count = collection.beatmap_hashes.len();
+                let mut seen = HashSet::new();
+                collection.beatmap_hashes.retain(|c| seen.insert(c.clone()));
+
+                collection.beatmap_hashes.len() != count
+            }
+        }
+    }
+}
+
+fn find_collection<'a>(
+    list: &'a mut CollectionList,
+    name: &str,
+) -> Option<&'a mut osu_db::collection::Collection> {
+    list.collections
+        .iter_mut()
+        .find(|c| c.name.as_deref().unwrap_or_default() == name)
+}
+
+enum WriterMessage {
+    Changed,
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+struct WriterState {
+    list: Arc<RwLock<CollectionList>>,
+    changes: Arc<Mutex<Vec<CollectionChange>>>,
+    path: PathBuf,
+    backups: usize,
+    when_running: GameRunningAction,
+    is_backed_up: bool,
+}
+
+/// `collection.db` loaded into memory. Changes are persisted by a single writer task,
+/// which debounces them and replaces the file atomically, so osu! never sees it half-written.
+pub struct CollectionStore {
+    list: Arc<RwLock<CollectionList>>,
+    changes: Arc<Mutex<Vec<CollectionChange>>>,
+    sender: mpsc::UnboundedSender<WriterMessage>,
+    /// Changes are only kept in memory, `collection.db` is never written.
+    dry_run: bool,
+}
+
+impl CollectionStore {
+    /// Loads `collection.db` along with changes deferred by a previous run (which are
+    /// applied as soon as osu! is closed). A timestamped copy of the original is kept
+    /// (up to `backups` of them) right before it's modified for the first time.
+    ///
+    /// With `dry_run`, nothing is ever written, changes can be listed through [`Self::changes`].
+    pub fn open(
+        path: &str,
+        backups: usize,
+        when_running: GameRunningAction,
+        dry_run: bool,
+    ) -> Result<CollectionStore, osu_db::Error> {
+        let mut list = CollectionList::from_file(path)?;
+        let pending = read_pending(&pending_path(Path::new(path)));
+
+        if !pending.is_empty() {
+            info!(
+                "applying {} collection changes deferred by a previous run",
+                pending.len()
+            );
+
+            for change in &pending {
+                change.apply(&mut list);
+            }
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let store = CollectionStore {
+            list: Arc::new(RwLock::new(list)),
+            changes: Arc::new(Mutex::new(pending)),
+            sender,
+            dry_run,
+        };
+
+        if !store.changes.lock().unwrap().is_empty() {
+            store.changed();
+        }
+
+        tokio::spawn(writer(
+            WriterState {
+                list: Arc::clone(&store.list),
+                changes: Arc::clone(&store.changes),
+                path: PathBuf::from(path),
+                backups,
+                when_running,
+                is_backed_up: false,
+            },
+            receiver,
+        ));
+
+        Ok(store)
+    }
+
+    /// Applies `change` to the in-memory copy, and schedules a write.
+    pub async fn apply(&self, change: CollectionChange) -> bool {
+        if !change.apply(&mut *self.list.write().await) {
+            return false;
+        }
+
+        self.changes.lock().unwrap().push(change);
+        self.changed();
+
+        true
+    }
+
+    /// Names of every collection, in the order osu! shows them.
+    pub async fn names(&self) -> Vec<String> {
+        self.list
+            .read()
+            .await
+            .collections
+            .iter()
+            .map(|c| c.name.clone().unwrap_or_default())
+            .collect()
+    }
+
+    /// Hashes of the collection called `name` (empty if it doesn't exist).
+    pub async fn hashes(&self, name: &str) -> Vec<String> {
+        self.list
+            .read()
+            .await
+            .collections
+            .iter()
+            .find(|c| c.name.as_deref().unwrap_or_default() == name)
+            .map(|c| c.beatmap_hashes.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Every change that will be (or was) written by this run, including deferred ones.
+    pub fn changes(&self) -> Vec<CollectionChange> {
+        self.changes.lock().unwrap().clone()
+    }
+
+    fn changed(&self) {
+        if self.dry_run {
+            return;
+        }
+
+        let _ = self.sender.send(WriterMessage::Changed);
+    }
+
+    /// Writes pending changes right away.
+    pub async fn flush(&self) -> io::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let (reply, response) = oneshot::channel();
+
+        self.sender
+            .send(WriterMessage::Flush(reply))
+            .map_err(|_| io::Error::other("collection writer is gone"))?;
+
+        response
+            .await
+            .map_err(|_| io::Error::other("collection writer is gone"))?
+    }
+}
+
+async fn writer(mut state: WriterState, mut receiver: mpsc::UnboundedReceiver<WriterMessage>) {
+    let mut deadline: Option<Instant> = None;
+    // changes that weren't written yet, kept after a failed write so the flush retries it
+    let mut dirty = false;
+
+    loop {
+        let message = match deadline {
+            Some(at) => tokio::select! {
+                message = receiver.recv() => message,
+                _ = tokio::time::sleep_until(at) => {
+                    deadline = None;
+
+                    match persist(&mut state).await {
+                        Ok(()) => dirty = false,
+                        Err(error) => warn!("collection.db couldn't be written, retrying later: {}", error),
+                    }
+
+                    continue;
+                }
+            },
+            None => receiver.recv().await,
+        };
+
+        match message {
+            Some(WriterMessage::Changed) => {
+                dirty = true;
+                deadline.get_or_insert_with(|| Instant::now() + FLUSH_DEBOUNCE);
+            }
+            Some(WriterMessage::Flush(reply)) => {
+                deadline = None;
+
+                let result = if dirty {
+                    persist(&mut state).await
+                } else {
+                    Ok(())
+                };
+                dirty = result.is_err();
+
+                let _ = reply.send(result);
+            }
+            None => {
+                if dirty && let Err(error) = persist(&mut state).await {
+                    error!("collection.db couldn't be written: {}", error);
+                }
+
+                break;
+            }
+        }
+    }
+}
+
+/// Checking the running processes can take a while, so it's kept off the runtime's threads.
+async fn is_game_running() -> bool {
+    tokio::task::spawn_blocking(osu::is_game_running)
+        .await
+        .unwrap_or(false)
+}
+
+/// osu! overwrites `collection.db` with its own copy when it's closed, so nothing is
+/// written while it's running: changes either wait for it to be closed, or are saved
+/// into a pending file that the next run will apply.
+async fn persist(state: &mut WriterState) -> io::Result<()> {
+    let pending_path = pending_path(&state.path);
+
+    if is_game_running().await {
+        match state.when_running {
+            GameRunningAction::Defer => {
+                let changes = serde_json::to_vec_pretty(&*state.changes.lock().unwrap())?;
+                tokio::fs::write(&pending_path, changes).await?;
+
+                warn!(
+                    "osu! is running, collection changes are saved to {} and will be applied on the next run.",
+                    pending_path.display()
+                );
+
+                return Ok(());
+            }
+            GameRunningAction::Wait => {
+                warn!(
+                    "osu! is running and would overwrite collection.db when closed, close it to save the collection changes."
+                );
+
+                while is_game_running().await {
+                    tokio::time::sleep(GAME_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    if !state.is_backed_up {
+        backup(&state.path, state.backups).await?;
+        state.is_backed_up = true;
+    }
+
+    // the file might've been changed by osu! in the meantime
+    let mut list = match tokio::fs::read(&state.path).await {
+        Ok(bytes) => CollectionList::from_bytes(&bytes).map_err(io::Error::other)?,
+        Err(_) => state.list.read().await.clone(),
+    };
+
+    for change in state.changes.lock().unwrap().iter() {
+        change.apply(&mut list);
+    }
+
+    let mut bytes = vec![];
+    list.to_writer(&mut bytes)?;
+
+    // written next to the original, so the rename stays on the same filesystem
+    let mut temp_path = state.path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = File::create(&temp_path).await?;
+    file.write_all(&bytes).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&temp_path, &state.path).await?;
+    *state.list.write().await = list;
+
+    if tokio::fs::try_exists(&pending_path).await? {
+        tokio::fs::remove_file(&pending_path).await?;
+    }
+
+    Ok(())
+}
+
+fn pending_path(path: &Path) -> PathBuf {
+    let mut pending_path = path.as_os_str().to_owned();
+    pending_path.push(".pending.json");
+
+    PathBuf::from(pending_path)
+}
+
+fn read_pending(path: &Path) -> Vec<CollectionChange> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|error| {
+            warn!(
+                "{} couldn't be parsed ({}), ignoring it",
+                path.display(),
+                error
+            );
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+/// Copies `collection.db` into `collection.db.<timestamp>.bak`, and removes the oldest
+/// backups so that only `keep` of them are left.
+async fn backup(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 || !tokio::fs::try_exists(path).await? {
+        return Ok(());
+    }
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let backup_path = path.with_file_name(format!(
+        "{}.{}.bak",
+        file_name,
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ));
+
+    tokio::fs::copy(path, &backup_path).await?;
+    info!("collection.db is backed up to {}", backup_path.display());
+
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let mut backups = vec![];
+
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if name.starts_with(&format!("{}.", file_name)) && name.ends_with(".bak") {
+            backups.push(entry.path());
+        }
+    }
+
+    // timestamps sort chronologically
+    backups.sort();
+    for old_backup in backups.iter().take(backups.len().saturating_sub(keep)) {
+        tokio::fs::remove_file(old_backup).await?;
+    }
+
+    Ok(())
+}
 
 pub fn format_collection_name(fmt: &str, collection: &Collection) -> String {
     fmt.replace("{collection_author}", &collection.uploader.username)
@@ -10,68 +483,204 @@ pub fn format_collection_name(fmt: &str, collection: &Collection) -> String {
         .replace("{collection_id}", collection.id.to_string().as_str())
 }
 
-pub async fn create_collection(
-    collection_list: Arc<RwLock<osu_db::CollectionList>>,
+pub fn format_tournament_name(
+    fmt: &str,
+    tournament: &Tournament,
+    round: &TournamentRound,
+) -> String {
+    fmt.replace("{tournament_id}", tournament.id.to_string().as_str())
+        .replace("{tournament}", &tournament.name)
+        .replace("{round}", &round.round)
+}
+
+pub async fn create_collection(store: &CollectionStore, name: &str) {
+    store
+        .apply(CollectionChange::Create {
+            name: name.to_string(),
+        })
+        .await;
+}
+
+pub async fn add_to_collection(store: &CollectionStore, name: &str, checksum: &str) -> bool {
+    store
+        .apply(CollectionChange::Add {
+            name: name.to_string(),
+            checksum: checksum.to_string(),
+        })
+        .await
+}
+
+pub async fn remove_from_collection(store: &CollectionStore, name: &str, checksum: &str) -> bool {
+    store
+        .apply(CollectionChange::Remove {
+            name: name.to_string(),
+            checksum: checksum.to_string(),
+        })
+        .await
+}
+
+/// Makes the collection called `name` contain exactly `checksums`: prints what will change,
+/// and removes everything that isn't in there anymore (moving it into `local_only_name`,
+/// when it's set). Missing checksums are added by the downloader as usual.
+pub async fn sync_collection(
+    store: &CollectionStore,
     name: &str,
-    path: &String,
+    checksums: &HashSet<&str>,
+    local_only_name: Option<&str>,
+    library: Option<&Library>,
 ) {
-    let collection_exists = collection_list
-        .read()
-        .await
-        .collections
+    let local = store.hashes(name).await;
+    let local_set = local.iter().map(String::as_str).collect::<HashSet<_>>();
+
+    let added = checksums.difference(&local_set).count();
+    let removed = local
         .iter()
-        .any(|collection| collection.name.as_ref().unwrap_or(&"".to_string()) == name);
-    if collection_exists {
-        return;
+        .filter(|c| !checksums.contains(c.as_str()))
+        .collect::<Vec<_>>();
+
+    info!(
+        "sync of {}: {} to add, {} to remove",
+        name,
+        added,
+        removed.len()
+    );
+
+    for checksum in &removed {
+        let description = match library {
+            Some(library) => library.describe(checksum),
+            None => checksum.to_string(),
+        };
+
+        match local_only_name {
+            Some(local_only_name) => info!("- {} (moved to {})", description, local_only_name),
+            None => info!("- {}", description),
+        }
     }
 
-    collection_list
-        .write()
-        .await
-        .collections
-        .push(osu_db::collection::Collection {
-            name: Some(name.to_string()),
-            beatmap_hashes: vec![],
-        });
+    if let Some(local_only_name) = local_only_name
+        && !removed.is_empty()
+    {
+        create_collection(store, local_only_name).await;
+    }
+
+    for checksum in removed {
+        if let Some(local_only_name) = local_only_name {
+            add_to_collection(store, local_only_name, checksum).await;
+        }
 
-    collection_list.read().await.to_file(path).unwrap();
+        remove_from_collection(store, name, checksum).await;
+    }
 }
 
-pub async fn add_to_collection(
-    collection_list: &Arc<RwLock<osu_db::CollectionList>>,
-    name: &str,
-    checksum: &String,
-) -> bool {
-    if is_checksum_in_collection(collection_list, name, checksum).await {
-        return false;
+#[cfg(test)]
+mod tests {
+    use osu_db::collection::Collection;
+
+    use super::*;
+
+    fn list(collections: &[(&str, &[&str])]) -> CollectionList {
+        CollectionList {
+            version: 20150203,
+            collections: collections
+                .iter()
+                .map(|(name, hashes)| Collection {
+                    name: Some(name.to_string()),
+                    beatmap_hashes: hashes.iter().map(|h| Some(h.to_string())).collect(),
+                })
+                .collect(),
+        }
     }
 
-    collection_list
-        .write()
-        .await
-        .collections
-        .iter_mut()
-        .find(|c| c.name.as_ref().unwrap_or(&"".to_string()) == name)
-        .unwrap()
-        .beatmap_hashes
-        .push(Some(checksum.to_string()));
+    fn contents(list: &CollectionList) -> Vec<(String, Vec<String>)> {
+        list.collections
+            .iter()
+            .map(|c| {
+                (
+                    c.name.clone().unwrap_or_default(),
+                    c.beatmap_hashes.iter().flatten().cloned().collect(),
+                )
+            })
+            .collect()
+    }
 
-    true
-}
+    fn change(change: &str, name: &str, other: &str) -> CollectionChange {
+        match change {
+            "create" => CollectionChange::Create { name: name.into() },
+            "add" => CollectionChange::Add {
+                name: name.into(),
+                checksum: other.into(),
+            },
+            "remove" => CollectionChange::Remove {
+                name: name.into(),
+                checksum: other.into(),
+            },
+            "rename" => CollectionChange::Rename {
+                name: name.into(),
+                new_name: other.into(),
+            },
+            "delete" => CollectionChange::Delete { name: name.into() },
+            "dedupe" => CollectionChange::Dedupe { name: name.into() },
+            _ => panic!("unknown change '{}'", change),
+        }
+    }
 
-async fn is_checksum_in_collection(
-    collection_list: &Arc<RwLock<osu_db::CollectionList>>,
-    name: &str,
-    checksum: &String,
-) -> bool {
-    collection_list
-        .write()
-        .await
-        .collections
-        .iter_mut()
-        .find(|c| c.name.as_ref().unwrap_or(&"".to_string()) == name)
-        .unwrap()
-        .beatmap_hashes
-        .iter()
-        .any(|c| c.as_ref().unwrap() == checksum)
+    #[test]
+    fn renames() {
+        let mut list = list(&[("a", &["1"]), ("b", &["2"])]);
+
+        assert!(change("rename", "a", "c").apply(&mut list));
+        assert_eq!(contents(&list)[0], ("c".to_string(), vec!["1".to_string()]));
+    }
+
+    #[test]
+    fn doesnt_rename_onto_an_existing_collection() {
+        let mut list = list(&[("a", &["1"]), ("b", &["2"])]);
+        let before = contents(&list);
+
+        assert!(!change("rename", "a", "b").apply(&mut list));
+        assert!(!change("rename", "missing", "c").apply(&mut list));
+        assert_eq!(contents(&list), before);
+    }
+
+    #[test]
+    fn deletes() {
+        let mut list = list(&[("a", &["1"]), ("b", &["2"])]);
+
+        assert!(change("delete", "a", "").apply(&mut list));
+        assert!(!change("delete", "missing", "").apply(&mut list));
+        assert_eq!(contents(&list), [("b".to_string(), vec!["2".to_string()])]);
+    }
+
+    #[test]
+    fn dedupes_keeping_the_first_occurrence() {
+        let mut list = list(&[("a", &["1", "2", "1", "3", "2"])]);
+
+        assert!(change("dedupe", "a", "").apply(&mut list));
+        assert_eq!(contents(&list)[0].1, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn changes_are_idempotent() {
+        let changes = [
+            change("create", "new", ""),
+            change("add", "new", "1"),
+            change("add", "a", "4"),
+            change("remove", "a", "2"),
+            change("dedupe", "a", ""),
+            change("rename", "b", "renamed"),
+            change("delete", "c", ""),
+        ];
+
+        let mut list = list(&[("a", &["1", "2", "3", "1"]), ("b", &["2"]), ("c", &[])]);
+        for change in &changes {
+            assert!(change.apply(&mut list), "{:?} didn't apply", change);
+        }
+        let applied = contents(&list);
+
+        // the writer replays every change of the run on each write
+        for change in &changes {
+            assert!(!change.apply(&mut list), "{:?} applied twice", change);
+        }
+        assert_eq!(contents(&list), applied);
+    }
 }
@@ -1,3 +1,5 @@
+pub mod archive;
 pub mod collection;
+pub mod osdb;
 pub mod osu;
 pub mod speedtest;
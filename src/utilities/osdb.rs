@@ -0,0 +1,441 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use chrono::{Local, NaiveDate};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+/// Version of the format written, Collection Manager reads every older one too.
+const VERSION: &str = "o!dm8";
+const EDITOR: &str = "ecstasy";
+/// Closes the file, as Collection Manager expects it.
+const FOOTER: &str = "By Piotrekol";
+/// Way over any name or hash, so a corrupted length fails instead of allocating gigabytes.
+const MAX_STRING_LENGTH: usize = 1 << 20;
+
+/// A collection as Collection Manager (by Piotrekol) stores it in `.osdb` files.
+pub struct OsdbCollection {
+    pub name: String,
+    /// osu!collector ID, 0 when it's unknown.
+    pub online_id: i32,
+    pub beatmaps: Vec<OsdbBeatmap>,
+    /// Beatmaps only known by their hash.
+    pub hashes: Vec<String>,
+}
+
+pub struct OsdbBeatmap {
+    pub beatmap_id: i32,
+    pub beatmapset_id: i32,
+    pub artist: String,
+    pub title: String,
+    pub version: String,
+    pub checksum: String,
+    pub comment: String,
+    /// 0 (osu!), 1 (taiko), 2 (catch) or 3 (mania).
+    pub mode: u8,
+    pub stars: f64,
+}
+
+pub fn write(path: &Path, collections: &[OsdbCollection]) -> io::Result<()> {
+    write_to(BufWriter::new(File::create(path)?), collections)
+}
+
+fn write_to(mut file: impl Write, collections: &[OsdbCollection]) -> io::Result<()> {
+    write_string(&mut file, VERSION)?;
+
+    let mut out = GzEncoder::new(file, Compression::default());
+    write_string(&mut out, VERSION)?;
+    out.write_all(&oa_date().to_le_bytes())?;
+    write_string(&mut out, EDITOR)?;
+    out.write_all(&(collections.len() as i32).to_le_bytes())?;
+
+    for collection in collections {
+        write_string(&mut out, &collection.name)?;
+        out.write_all(&collection.online_id.to_le_bytes())?;
+
+        out.write_all(&(collection.beatmaps.len() as i32).to_le_bytes())?;
+        for beatmap in &collection.beatmaps {
+            out.write_all(&beatmap.beatmap_id.to_le_bytes())?;
+            out.write_all(&beatmap.beatmapset_id.to_le_bytes())?;
+            write_string(&mut out, &beatmap.artist)?;
+            write_string(&mut out, &beatmap.title)?;
+            write_string(&mut out, &beatmap.version)?;
+            write_string(&mut out, &beatmap.checksum)?;
+            write_string(&mut out, &beatmap.comment)?;
+            out.write_all(&[beatmap.mode])?;
+            out.write_all(&beatmap.stars.to_le_bytes())?;
+        }
+
+        out.write_all(&(collection.hashes.len() as i32).to_le_bytes())?;
+        for checksum in &collection.hashes {
+            write_string(&mut out, checksum)?;
+        }
+    }
+
+    write_string(&mut out, FOOTER)?;
+    out.finish()?.flush()
+}
+
+/// .NET's `BinaryWriter.Write(string)`: UTF-8, prefixed by its length as a 7-bit encoded integer.
+fn write_string(out: &mut impl Write, value: &str) -> io::Result<()> {
+    let mut length = value.len();
+
+    while length >= 0x80 {
+        out.write_all(&[(length as u8) | 0x80])?;
+        length >>= 7;
+    }
+
+    out.write_all(&[length as u8])?;
+    out.write_all(value.as_bytes())
+}
+
+/// Now as an OLE Automation date, days since 1899-12-30 (what .NET's `DateTime.ToOADate` gives).
+fn oa_date() -> f64 {
+    let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+
+    (Local::now().naive_local() - epoch).num_milliseconds() as f64 / 86_400_000.0
+}
+
+/// Reads any version of the format, from the first `o!dm` to `o!dm8` (and their "min" variants,
+/// without metadata).
+pub fn read(path: &Path) -> io::Result<Vec<OsdbCollection>> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+fn read_from<'a>(mut file: impl Read + 'a) -> io::Result<Vec<OsdbCollection>> {
+    let version_string = read_string(&mut file)?;
+    let is_minimal = version_string.ends_with("min");
+    let version = match version_string.trim_end_matches("min") {
+        "o!dm" => 1,
+        version => version
+            .strip_prefix("o!dm")
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|v| (2..=8).contains(v))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported .osdb version '{}'", version_string),
+                )
+            })?,
+    };
+
+    // from version 7, everything after the version is compressed (and starts with it again)
+    let mut input: Box<dyn Read + 'a> = if version >= 7 {
+        let mut input = GzDecoder::new(file);
+        read_string(&mut input)?;
+        Box::new(input)
+    } else {
+        Box::new(file)
+    };
+
+    let _date = read_f64(&mut input)?;
+    let _editor = read_string(&mut input)?;
+    let collection_count = read_i32(&mut input)?;
+
+    let mut collections = vec![];
+    for _ in 0..collection_count {
+        let mut collection = OsdbCollection {
+            name: read_string(&mut input)?,
+            online_id: if version >= 7 {
+                read_i32(&mut input)?
+            } else {
+                0
+            },
+            beatmaps: vec![],
+            hashes: vec![],
+        };
+
+        let beatmap_count = read_i32(&mut input)?;
+        for _ in 0..beatmap_count {
+            let beatmap_id = read_i32(&mut input)?;
+            let beatmapset_id = if version >= 2 {
+                read_i32(&mut input)?
+            } else {
+                -1
+            };
+
+            let (artist, title, version_name) = if is_minimal {
+                (String::new(), String::new(), String::new())
+            } else {
+                (
+                    read_string(&mut input)?,
+                    read_string(&mut input)?,
+                    read_string(&mut input)?,
+                )
+            };
+
+            let checksum = read_string(&mut input)?;
+            let comment = if version >= 4 {
+                read_string(&mut input)?
+            } else {
+                String::new()
+            };
+            let mode = if version >= 8 || (version >= 5 && !is_minimal) {
+                read_u8(&mut input)?
+            } else {
+                0
+            };
+            let stars = if version >= 8 || (version >= 6 && !is_minimal) {
+                read_f64(&mut input)?
+            } else {
+                0.0
+            };
+
+            collection.beatmaps.push(OsdbBeatmap {
+                beatmap_id,
+                beatmapset_id,
+                artist,
+                title,
+                version: version_name,
+                checksum,
+                comment,
+                mode,
+                stars,
+            });
+        }
+
+        if version >= 3 {
+            let hash_count = read_i32(&mut input)?;
+            for _ in 0..hash_count {
+                collection.hashes.push(read_string(&mut input)?);
+            }
+        }
+
+        collections.push(collection);
+    }
+
+    Ok(collections)
+}
+
+fn read_string(input: &mut impl Read) -> io::Result<String> {
+    let mut length = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_u8(input)?;
+        length |= ((byte & 0x7f) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+
+        shift += 7;
+        if shift > 28 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid string length",
+            ));
+        }
+    }
+
+    if length > MAX_STRING_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("string of {} bytes", length),
+        ));
+    }
+
+    let mut bytes = vec![0; length];
+    input.read_exact(&mut bytes)?;
+
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_i32(input: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_f64(input: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beatmap(beatmap_id: i32, artist: &str, title: &str) -> OsdbBeatmap {
+        OsdbBeatmap {
+            beatmap_id,
+            beatmapset_id: beatmap_id / 10,
+            artist: artist.to_string(),
+            title: title.to_string(),
+            version: "Insane".to_string(),
+            checksum: format!("{:032x}", beatmap_id),
+            comment: String::new(),
+            mode: 3,
+            stars: 5.25,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        // over 127 bytes, so its length takes two bytes
+        let long_name = "東方 ".repeat(30);
+        let collections = vec![
+            OsdbCollection {
+                name: long_name.clone(),
+                online_id: 6220,
+                beatmaps: vec![beatmap(1030499, "Camellia", "Ωmega Rhythm")],
+                hashes: vec!["d41d8cd98f00b204e9800998ecf8427e".to_string()],
+            },
+            OsdbCollection {
+                name: "empty".to_string(),
+                online_id: 0,
+                beatmaps: vec![],
+                hashes: vec![],
+            },
+        ];
+
+        let mut bytes = vec![];
+        write_to(&mut bytes, &collections).unwrap();
+        let read = read_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].name, long_name);
+        assert_eq!(read[0].online_id, 6220);
+        assert_eq!(read[0].hashes, collections[0].hashes);
+
+        let (read_beatmap, beatmap) = (&read[0].beatmaps[0], &collections[0].beatmaps[0]);
+        assert_eq!(read_beatmap.beatmap_id, beatmap.beatmap_id);
+        assert_eq!(read_beatmap.beatmapset_id, beatmap.beatmapset_id);
+        assert_eq!(read_beatmap.artist, beatmap.artist);
+        assert_eq!(read_beatmap.title, beatmap.title);
+        assert_eq!(read_beatmap.version, beatmap.version);
+        assert_eq!(read_beatmap.checksum, beatmap.checksum);
+        assert_eq!(read_beatmap.mode, beatmap.mode);
+        assert_eq!(read_beatmap.stars, beatmap.stars);
+
+        assert_eq!(read[1].name, "empty");
+        assert!(read[1].beatmaps.is_empty());
+    }
+
+    /// The header of the uncompressed versions (before `o!dm7`).
+    fn legacy_header(version: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_string(&mut bytes, version).unwrap();
+        bytes.extend(0.0f64.to_le_bytes());
+        write_string(&mut bytes, "someone").unwrap();
+        bytes.extend(1i32.to_le_bytes());
+        write_string(&mut bytes, "old").unwrap();
+        bytes
+    }
+
+    #[test]
+    fn reads_first_version() {
+        let mut bytes = legacy_header("o!dm");
+        bytes.extend(1i32.to_le_bytes());
+        bytes.extend(75i32.to_le_bytes());
+        write_string(&mut bytes, "Artist").unwrap();
+        write_string(&mut bytes, "Title").unwrap();
+        write_string(&mut bytes, "Hard").unwrap();
+        write_string(&mut bytes, "0123456789abcdef0123456789abcdef").unwrap();
+
+        let collections = read_from(bytes.as_slice()).unwrap();
+        let beatmap = &collections[0].beatmaps[0];
+
+        assert_eq!(collections[0].name, "old");
+        assert_eq!(collections[0].online_id, 0);
+        assert_eq!(beatmap.beatmap_id, 75);
+        assert_eq!(beatmap.beatmapset_id, -1);
+        assert_eq!(beatmap.title, "Title");
+        assert_eq!(beatmap.checksum, "0123456789abcdef0123456789abcdef");
+        assert_eq!(beatmap.stars, 0.0);
+        assert!(collections[0].hashes.is_empty());
+    }
+
+    #[test]
+    fn reads_version_6() {
+        let mut bytes = legacy_header("o!dm6");
+        bytes.extend(1i32.to_le_bytes());
+        bytes.extend(75i32.to_le_bytes());
+        bytes.extend(1i32.to_le_bytes());
+        write_string(&mut bytes, "Artist").unwrap();
+        write_string(&mut bytes, "Title").unwrap();
+        write_string(&mut bytes, "Hard").unwrap();
+        write_string(&mut bytes, "0123456789abcdef0123456789abcdef").unwrap();
+        write_string(&mut bytes, "a comment").unwrap();
+        bytes.push(1);
+        bytes.extend(4.5f64.to_le_bytes());
+        bytes.extend(1i32.to_le_bytes());
+        write_string(&mut bytes, "fedcba9876543210fedcba9876543210").unwrap();
+
+        let collections = read_from(bytes.as_slice()).unwrap();
+        let beatmap = &collections[0].beatmaps[0];
+
+        assert_eq!(beatmap.beatmapset_id, 1);
+        assert_eq!(beatmap.version, "Hard");
+        assert_eq!(beatmap.comment, "a comment");
+        assert_eq!(beatmap.mode, 1);
+        assert_eq!(beatmap.stars, 4.5);
+        assert_eq!(collections[0].hashes, ["fedcba9876543210fedcba9876543210"]);
+    }
+
+    #[test]
+    fn reads_minimal_version_8() {
+        let mut bytes = vec![];
+        write_string(&mut bytes, "o!dm8min").unwrap();
+
+        let mut out = GzEncoder::new(bytes, Compression::default());
+        write_string(&mut out, "o!dm8min").unwrap();
+        out.write_all(&0.0f64.to_le_bytes()).unwrap();
+        write_string(&mut out, "someone").unwrap();
+        out.write_all(&1i32.to_le_bytes()).unwrap();
+        write_string(&mut out, "minimal").unwrap();
+        out.write_all(&6220i32.to_le_bytes()).unwrap();
+        out.write_all(&1i32.to_le_bytes()).unwrap();
+        out.write_all(&75i32.to_le_bytes()).unwrap();
+        out.write_all(&1i32.to_le_bytes()).unwrap();
+        write_string(&mut out, "0123456789abcdef0123456789abcdef").unwrap();
+        write_string(&mut out, "").unwrap();
+        out.write_all(&[3]).unwrap();
+        out.write_all(&6.0f64.to_le_bytes()).unwrap();
+        out.write_all(&0i32.to_le_bytes()).unwrap();
+        write_string(&mut out, FOOTER).unwrap();
+        let bytes = out.finish().unwrap();
+
+        let collections = read_from(bytes.as_slice()).unwrap();
+        let beatmap = &collections[0].beatmaps[0];
+
+        assert_eq!(collections[0].name, "minimal");
+        assert_eq!(collections[0].online_id, 6220);
+        assert_eq!(beatmap.beatmap_id, 75);
+        assert_eq!(beatmap.artist, "");
+        assert_eq!(beatmap.checksum, "0123456789abcdef0123456789abcdef");
+        assert_eq!(beatmap.mode, 3);
+        assert_eq!(beatmap.stars, 6.0);
+    }
+
+    #[test]
+    fn rejects_oversized_strings() {
+        // a length of 2^28 - 1 bytes, with nothing after it
+        let bytes = [0xff, 0xff, 0xff, 0x7f];
+        let error = read_string(&mut bytes.as_slice()).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn writes_multi_byte_string_lengths() {
+        let mut bytes = vec![];
+        write_string(&mut bytes, &"a".repeat(300)).unwrap();
+
+        // 300 = 0b10_0101100, low 7 bits first
+        assert_eq!(bytes[..2], [0xac, 0x02]);
+        assert_eq!(read_string(&mut bytes.as_slice()).unwrap(), "a".repeat(300));
+    }
+}
@@ -1,18 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::PathBuf,
+};
+
 use tokio::fs::read_dir;
 
-pub async fn find_beatmap(path: &str, id: i32) -> Option<bool> {
+#[derive(Clone, Debug)]
+pub struct LocalBeatmap {
+    pub beatmap_id: i32,
+    pub beatmapset_id: i32,
+    pub artist: String,
+    pub title: String,
+    pub version: String,
+    /// 0 (osu!), 1 (taiko), 2 (catch) or 3 (mania).
+    pub mode: u8,
+    /// Without mods, in its own mode.
+    pub stars: f64,
+}
+
+impl fmt::Display for LocalBeatmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - {} [{}]", self.artist, self.title, self.version)
+    }
+}
+
+/// Beatmaps osu! knows about, read from `osu!.db`.
+pub struct Library {
+    /// Keyed by the MD5 of the `.osu` file.
+    pub beatmaps: HashMap<String, LocalBeatmap>,
+    pub beatmapsets: HashSet<i32>,
+}
+
+impl Library {
+    pub fn contains(&self, beatmapset_id: i32, checksums: &[&str]) -> bool {
+        self.beatmapsets.contains(&beatmapset_id)
+            || checksums.iter().any(|c| self.beatmaps.contains_key(*c))
+    }
+
+    /// Difficulties osu! doesn't have, e.g. when the local copy is an older revision.
+    pub fn missing<'a>(&self, checksums: &[&'a str]) -> Vec<&'a str> {
+        checksums
+            .iter()
+            .filter(|c| !self.beatmaps.contains_key(**c))
+            .copied()
+            .collect()
+    }
+
+    /// `artist - title [version]` of a difficulty, or the checksum itself when osu! doesn't know it.
+    pub fn describe(&self, checksum: &str) -> String {
+        self.beatmaps
+            .get(checksum)
+            .map(|b| b.to_string())
+            .unwrap_or(checksum.to_string())
+    }
+}
+
+pub async fn load_library(path: &str) -> Result<Library, osu_db::Error> {
+    let path = path.to_string();
+    let listing = tokio::task::spawn_blocking(move || osu_db::Listing::from_file(path))
+        .await
+        .expect("osu!.db loading task panicked")?;
+
+    Ok(Library {
+        beatmaps: listing
+            .beatmaps
+            .iter()
+            .filter_map(|b| {
+                let ratings = match b.mode {
+                    osu_db::Mode::Standard => &b.std_ratings,
+                    osu_db::Mode::Taiko => &b.taiko_ratings,
+                    osu_db::Mode::CatchTheBeat => &b.ctb_ratings,
+                    osu_db::Mode::Mania => &b.mania_ratings,
+                };
+
+                let beatmap = LocalBeatmap {
+                    beatmap_id: b.beatmap_id,
+                    beatmapset_id: b.beatmapset_id,
+                    artist: b
+                        .artist_ascii
+                        .clone()
+                        .or(b.artist_unicode.clone())
+                        .unwrap_or_default(),
+                    title: b
+                        .title_ascii
+                        .clone()
+                        .or(b.title_unicode.clone())
+                        .unwrap_or_default(),
+                    version: b.difficulty_name.clone().unwrap_or_default(),
+                    mode: b.mode.raw(),
+                    stars: ratings
+                        .iter()
+                        .find(|(mods, _)| mods.bits() == 0)
+                        .map(|(_, stars)| *stars)
+                        .unwrap_or_default(),
+                };
+
+                Some((b.hash.clone()?, beatmap))
+            })
+            .collect(),
+        beatmapsets: listing
+            .beatmaps
+            .iter()
+            .map(|b| b.beatmapset_id)
+            .filter(|id| *id > 0)
+            .collect(),
+    })
+}
+
+/// Indexes the `<id> <artist> - <title>` (or only `<id>`, when its metadata wasn't known)
+/// folders and `.osz` archives of the Songs folder by beatmapset ID. An archive wins over a
+/// folder with the same ID, as it means osu! didn't import the latest download yet.
+pub async fn index_songs(path: &str) -> HashMap<i32, PathBuf> {
     let mut entries = read_dir(path).await.unwrap();
+    let mut songs = HashMap::new();
 
     while let Some(entry) = entries.next_entry().await.unwrap() {
         let file_name = entry.file_name();
-        let file_name_str = file_name.to_string_lossy();
+        let file_name = file_name.to_string_lossy();
+
+        let digits = file_name
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(file_name.len());
+        let (id, rest) = file_name.split_at(digits);
 
-        if file_name_str.contains(&id.to_string()) {
-            return Some(true);
+        let Ok(id) = id.parse::<i32>() else {
+            continue;
+        };
+
+        // `.part` files are downloads to resume, not installed beatmapsets
+        let is_named = rest.starts_with(' ') && !rest.ends_with(".part");
+
+        if rest == ".osz" || (is_named && rest.ends_with(".osz")) {
+            songs.insert(id, entry.path());
+        } else if rest.is_empty() || is_named {
+            songs.entry(id).or_insert_with(|| entry.path());
         }
     }
 
-    None
+    songs
+}
+
+/// Whether osu! (stable) is running, natively or through Wine.
+#[cfg(target_os = "windows")]
+pub fn is_game_running() -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/FI", "IMAGENAME eq osu!.exe", "/NH"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains("osu!.exe"))
+        .unwrap_or(false)
+}
+
+/// Whether osu! (stable) is running, natively or through Wine.
+#[cfg(target_os = "linux")]
+pub fn is_game_running() -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+
+    entries.flatten().any(|entry| {
+        std::fs::read(entry.path().join("cmdline"))
+            .map(|cmdline| String::from_utf8_lossy(&cmdline).contains("osu!.exe"))
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub fn is_game_running() -> bool {
+    false
 }
 
 #[cfg(target_os = "windows")]
@@ -48,3 +203,38 @@ pub async fn find_game() -> Result<String, std::io::Error> {
         .expect("'OSU_FOLDER' export is not defined (e.g.: '$HOME\\osu')");
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn indexes_songs() {
+        let songs_path = std::env::temp_dir().join(format!("ecstasy-songs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&songs_path);
+        std::fs::create_dir_all(&songs_path).unwrap();
+
+        for folder in ["1234 Artist - Title", "75", "41234 Foo"] {
+            std::fs::create_dir(songs_path.join(folder)).unwrap();
+        }
+        for file in [
+            "1234 Artist - Title.osz",
+            "5678 Artist - Title.osz.catboy.best.part",
+            "9012.osz.osu.direct.part",
+            "notes.txt",
+        ] {
+            std::fs::write(songs_path.join(file), []).unwrap();
+        }
+
+        let songs = index_songs(songs_path.to_str().unwrap()).await;
+        std::fs::remove_dir_all(&songs_path).unwrap();
+
+        // the archive of a newer revision wins over the installed folder
+        assert_eq!(songs[&1234], songs_path.join("1234 Artist - Title.osz"));
+        assert_eq!(songs[&75], songs_path.join("75"));
+        assert_eq!(songs[&41234], songs_path.join("41234 Foo"));
+        assert!(!songs.contains_key(&5678));
+        assert!(!songs.contains_key(&9012));
+        assert_eq!(songs.len(), 3);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::VecDeque, time::Instant};
+use std::{collections::HashMap, time::Instant};
 
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::{config::MirrorType, mirrors::Ratelimiter};
+use crate::config::MirrorType;
 
 async fn test_download(client: &reqwest::Client, payload_size_bytes: usize) -> f64 {
     let req = client.get(format!(
@@ -35,16 +35,24 @@ pub async fn benchmark() {
     info!("running speedtest (mirror)\n");
 
     let mut file_size: f64 = 0.0;
-    let mut mirror_speed: VecDeque<f64> = VecDeque::new();
+    // seconds it took, by mirror name
+    let mut mirror_speed: HashMap<&str, f64> = HashMap::new();
 
-    for (index, mirror_type) in MirrorType::ALL.iter().enumerate() {
+    let mirror_types = MirrorType::all();
+
+    for (index, mirror_type) in mirror_types.iter().enumerate() {
         let mirror = mirror_type.get_mirror();
-        let rate_limiter = Ratelimiter::default();
 
         let start = Instant::now();
 
-        let file = mirror.get_file(1030499, &rate_limiter).await.unwrap();
-        if file.len() as f64 <= 0.0 {
+        let file = match mirror.get_file(1030499).await {
+            Ok(file) => file,
+            Err(error) => {
+                warn!("{} failed, skipping it: {}", mirror.get_name(), error);
+                continue;
+            }
+        };
+        if file.is_empty() {
             continue;
         }
 
@@ -54,10 +62,10 @@ pub async fn benchmark() {
         info!(
             "{} done, {} to go.",
             mirror.get_name(),
-            MirrorType::ALL.len() - (index + 1)
+            mirror_types.len() - (index + 1)
         );
 
-        mirror_speed.push_front(start.elapsed().as_secs_f64());
+        mirror_speed.insert(mirror.get_name(), start.elapsed().as_secs_f64());
     }
 
     // results
@@ -72,18 +80,17 @@ pub async fn benchmark() {
         file_size / 1024.0 / 1024.0
     );
 
-    for mirror_type in MirrorType::ALL {
+    for mirror_type in &mirror_types {
         let mirror = mirror_type.get_mirror();
-        let mirror_speed = mirror_speed.pop_front();
-
-        if mirror_speed.is_none() {
+        let Some(seconds) = mirror_speed.get(mirror.get_name()) else {
+            info!("{} = failed", mirror.get_name());
             continue;
-        }
+        };
 
         info!(
             "{} = {:.2}Mb/s",
             mirror.get_name(),
-            (file_size * 8.0 / 1_000_000.0) / mirror_speed.unwrap()
+            (file_size * 8.0 / 1_000_000.0) / seconds
         );
     }
 }